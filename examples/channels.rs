@@ -16,6 +16,7 @@ extern crate docopt;
 extern crate rustc_serialize;
 use futures::Future;
 use std::str;
+use std::sync::Arc;
 use futures::{future, Stream,Sink};
 use std::thread;
 use futures::sync::mpsc;
@@ -43,9 +44,7 @@ struct Args {
     flag_addr: String
 }
 
-// TODO: add ModbusRTUCodec
-
-use modbus_server::BlankRegisters;
+use modbus_server::{BlankRegisters, Diagnostics, ModbusBlock};
 
 pub struct ModbusService {
     in_: mpsc::Sender<(ModbusRequestPDU,
@@ -88,26 +87,36 @@ impl Service for ModbusService {
     }
 }
 
+// The actor: owns the block and answers requests one at a time.
+fn run_block<B: ModbusBlock>(
+    mut block: B,
+    req_out: mpsc::Receiver<(ModbusRequestPDU, oneshot::Sender<ModbusResponsePDU>)>) {
+    let mut core = Core::new().unwrap();
+    core.run(req_out.map(
+        |(req, tx)|{
+            println!("Sending {:?}",req);
+            tx.complete(block.call(req))
+        }).for_each(|e| Ok(()))).unwrap();
+}
+
 fn main() {
 
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| {println!("DAMN {:?}",e); e.exit()});
     println!("{:?}", args);
+    let diagnostics = Arc::new(Diagnostics::new());
+    let block_diagnostics = diagnostics.clone();
     let (in_,req_out)= mpsc::channel::<(ModbusRequestPDU,oneshot::Sender<ModbusResponsePDU>)>(1);
     thread::spawn(move ||{
         
         let mut block = BlankRegisters::new();
-        let mut core = Core::new().unwrap();
-        core.run(req_out.map(
-            |(req, tx)|{
-                println!("Sending {:?}",req);
-                tx.complete(block.call(req))
-            }).for_each(|e| Ok(()))).unwrap();
+        block.set_diagnostics(block_diagnostics);
+        run_block(block, req_out);
     });
     
 
-    TcpServer::new(ModbusTCPProto, args.flag_addr.parse().unwrap())
+    TcpServer::new(ModbusTCPProto::new(diagnostics), args.flag_addr.parse().unwrap())
         .serve(move || Ok(ModbusService::new(in_.clone())));
 }
 
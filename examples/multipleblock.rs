@@ -38,23 +38,22 @@ struct Args {
     flag_addr: String
 }
 
-// TODO: add ModbusRTUCodec
+use modbus_server::{BlankRegisters, ModbusBlock};
+use modbus_server::{DeviceIdentification, Diagnostics, identification};
 
-use modbus_server::BlankRegisters;
-
-pub struct ModbusService {
-    blocks:HashMap<u8,Arc<Mutex<BlankRegisters>>>
+pub struct ModbusService<B> {
+    blocks:HashMap<u8,Arc<Mutex<B>>>
 }
 
-impl ModbusService {
+impl<B: ModbusBlock> ModbusService<B> {
     fn new (
-        blocks:HashMap<u8,Arc<Mutex<BlankRegisters>>>)->ModbusService {
+        blocks:HashMap<u8,Arc<Mutex<B>>>)->ModbusService<B> {
         ModbusService{ blocks:blocks}
     }
     
 }
 
-impl Service for ModbusService {
+impl<B: ModbusBlock> Service for ModbusService<B> {
     
     type Request = ModbusTCPRequest;
     type Response = ModbusTCPResponse;    
@@ -63,10 +62,10 @@ impl Service for ModbusService {
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let mut a = self.blocks[&req.header.uid].lock().unwrap();
+        let pdu = a.call_from(None, req.header.uid, req.pdu);
         future::finished(Self::Response {
             header:req.header,
-            pdu:
-            a.call(req.pdu)
+            pdu:pdu
         })
     }
 }
@@ -80,14 +79,22 @@ fn main() {
     println!("{:?}", args);
     
     let mut blocks : HashMap<u8,Arc<Mutex<BlankRegisters>>> = HashMap::new();
+    // One set of counters for the whole port.
+    let diagnostics = Arc::new(Diagnostics::new());
     for r in args.arg_resource {
-        let block = Arc::new(Mutex::new(BlankRegisters::new()));
+        let mut registers = BlankRegisters::new();
+        let mut ident = DeviceIdentification::default();
+        ident.set_object(identification::PRODUCT_NAME, format!("unit {}", r));
+        registers.set_device_identification(ident);
+        registers.set_diagnostics(diagnostics.clone());
+        registers.set_unit_id(r);
+        let block = Arc::new(Mutex::new(registers));
         blocks.insert(r,block);
     }
 
 
     
-    TcpServer::new(ModbusTCPProto, args.flag_addr.parse().unwrap())
+    TcpServer::new(ModbusTCPProto::new(diagnostics), args.flag_addr.parse().unwrap())
         .serve(move || Ok(ModbusService::new(blocks.clone())));
 }
 
@@ -0,0 +1,83 @@
+//
+/*
+  Serve RTU frames over TCP, for masters behind a serial-to-Ethernet
+  converter that forwards the raw RS-485 traffic.
+*/ 
+#![feature(inclusive_range_syntax)] 
+#![feature(type_ascription)]
+#![feature(more_struct_aliases)]
+
+extern crate modbus_server;
+extern crate futures;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+extern crate docopt;
+extern crate rustc_serialize;
+
+use std::sync::{Arc,Mutex};
+use std::str;
+use futures::{future};
+use docopt::Docopt;
+use std::io::{self};
+use tokio_proto::TcpServer;
+use tokio_service::Service;
+    
+use modbus_server::{ModbusRTUProto,ModbusRTUResponse,ModbusRTURequest};
+
+const USAGE: &'static str = "
+Usage: rtuovertcp [options]
+
+Options:
+    --addr=<addr>  # Base URL  [default: 127.0.0.1:502].
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_addr: String
+}
+
+use modbus_server::{BlankRegisters, ModbusBlock};
+
+pub struct ModbusService<B> {
+    block:Arc<Mutex<B>>
+}
+
+impl<B: ModbusBlock> ModbusService<B> {
+    fn new (
+        block:Arc<Mutex<B>>)->ModbusService<B> {
+        ModbusService{ block:block}
+    }
+    
+}
+
+impl<B: ModbusBlock> Service for ModbusService<B> {
+    
+    type Request = ModbusRTURequest;
+    type Response = ModbusRTUResponse;    
+    type Error = io::Error;
+    type Future = future::FutureResult<Self::Response, Self::Error>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let mut a = self.block.lock().unwrap();
+        let pdu = a.call_from(None, req.slave, req.pdu);
+        future::finished(Self::Response {
+            slave:req.slave,
+            pdu:pdu
+        })
+    }
+}
+
+fn main() {
+    let registers = BlankRegisters::new();
+    let diagnostics = registers.diagnostics();
+    let block = Arc::new(Mutex::new(registers));
+
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.decode())
+        .unwrap_or_else(|e| {println!("DAMN {:?}",e); e.exit()});
+    println!("{:?}", args);
+    
+    TcpServer::new(ModbusRTUProto::new(diagnostics), args.flag_addr.parse().unwrap())
+        .serve(move || Ok(ModbusService::new(block.clone())));
+}
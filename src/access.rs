@@ -0,0 +1,154 @@
+// Which addresses masters may read and write, checked before a request
+// reaches the block. Each unit's block has its own rules.
+//
+// Everything is readable and writable until a rule says otherwise. Where
+// rules overlap, an access is allowed only if every rule covering it allows
+// it, and the exception is the one from the first rule that refuses it.
+
+use byteorder::{BigEndian, ByteOrder};
+use enum_primitive::FromPrimitive;
+
+use modbus;
+use {Address, Code, FunctionCode, ModbusRequestPDU, ModbusResponsePDU, Quantity, Table};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+    NoAccess
+}
+
+impl Access {
+    fn allows(&self, write: bool) -> bool {
+        match *self {
+            Access::ReadWrite => true,
+            Access::ReadOnly => !write,
+            Access::WriteOnly => write,
+            Access::NoAccess => false
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Rule {
+    table: Table,
+    // Half open, so that a rule can run up to the last address.
+    start: usize,
+    end: usize,
+    access: Access,
+    exception_code: u8
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct AccessRules {
+    rules: Vec<Rule>
+}
+
+// A range of a table a request reads or writes.
+struct Touch {
+    table: Table,
+    address: Address,
+    quantity: Quantity,
+    write: bool
+}
+
+fn touch(table: Table, address: Address, quantity: Quantity, write: bool) -> Touch {
+    Touch { table: table, address: address, quantity: quantity, write: write }
+}
+
+// What a request would touch if it went through. Requests for functions
+// that don't use the four tables touch nothing.
+fn touches(req: &ModbusRequestPDU) -> Vec<Touch> {
+    let function = match FunctionCode::from_u8(req.code) {
+        Some(function) => function,
+        None => return Vec::new()
+    };
+    let (address, quantity) = (req.address, req.q_or_v);
+    match function {
+        FunctionCode::ReadCoils => vec![touch(Table::Coils, address, quantity, false)],
+        FunctionCode::ReadDiscreteInputs => vec![touch(Table::DiscreteInputs, address, quantity, false)],
+        FunctionCode::ReadHoldingRegisters => vec![touch(Table::HoldingRegisters, address, quantity, false)],
+        FunctionCode::ReadInputRegisters => vec![touch(Table::InputRegisters, address, quantity, false)],
+        FunctionCode::WriteSingleCoil => vec![touch(Table::Coils, address, 1, true)],
+        FunctionCode::WriteSingleRegister => vec![touch(Table::HoldingRegisters, address, 1, true)],
+        FunctionCode::WriteMultipleCoils => vec![touch(Table::Coils, address, quantity, true)],
+        FunctionCode::WriteMultipleRegisters => vec![touch(Table::HoldingRegisters, address, quantity, true)],
+        FunctionCode::MaskWriteRegister => vec![touch(Table::HoldingRegisters, address, 1, false),
+                                                touch(Table::HoldingRegisters, address, 1, true)],
+        FunctionCode::ReadWriteMultipleRegisters => {
+            let mut touched = vec![touch(Table::HoldingRegisters, address, quantity, false)];
+            // The footer starts with the write address and quantity.
+            if let Some(ref addl) = req.addl {
+                if addl.data.len() >= 4 {
+                    touched.push(touch(Table::HoldingRegisters,
+                                       BigEndian::read_u16(&addl.data[0..2]),
+                                       BigEndian::read_u16(&addl.data[2..4]),
+                                       true));
+                }
+            }
+            touched
+        },
+        // The count at the pointer address is read along with the queue.
+        FunctionCode::ReadFIFOQueue => vec![touch(Table::HoldingRegisters, address, 1, false)],
+        _ => Vec::new()
+    }
+}
+
+impl AccessRules {
+
+    pub fn new() -> AccessRules {
+        AccessRules::default()
+    }
+
+    // Refused accesses fail with IllegalDataAddress.
+    pub fn add(&mut self, table: Table, start: Address, size: usize, access: Access) {
+        self.add_with_exception(table, start, size, access,
+                                modbus::ExceptionCode::IllegalDataAddress as u8);
+    }
+
+    pub fn add_with_exception(&mut self, table: Table, start: Address, size: usize,
+                              access: Access, exception_code: u8) {
+        self.rules.push(Rule {
+            table: table,
+            start: start as usize,
+            end: start as usize + size,
+            access: access,
+            exception_code: exception_code
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    fn refusal(&self, touched: &Touch) -> Option<u8> {
+        let start = touched.address as usize;
+        let end = start + touched.quantity as usize;
+        self.rules.iter()
+            .find(|rule| {
+                rule.table == touched.table && rule.start < end && start < rule.end &&
+                    !rule.access.allows(touched.write)
+            })
+            .map(|rule| rule.exception_code)
+    }
+
+    // The exception response for a request the rules refuse, or None if it
+    // may go ahead.
+    pub fn check(&self, req: &ModbusRequestPDU) -> Option<ModbusResponsePDU> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        touches(req).iter()
+            .filter_map(|touched| self.refusal(touched))
+            .next()
+            .map(|exception_code| exception(req.code, exception_code))
+    }
+}
+
+fn exception(code: Code, exception_code: u8) -> ModbusResponsePDU {
+    ModbusResponsePDU::ModbusErrorResponse {
+        code: code | 0x80,
+        exception_code: exception_code
+    }
+}
@@ -0,0 +1,160 @@
+// Modbus ASCII framing, used by older serial devices:
+//
+//   ':' | hex(slave address, PDU, LRC) | CR LF
+//
+// Every byte is sent as two hexadecimal characters, and the frame is
+// checked with a longitudinal redundancy check instead of a CRC.
+
+use tokio_core::io::{Codec, EasyBuf};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use {ModbusRequestPDU, ModbusResponsePDU, Diagnostics};
+use {parse_modbus_request_pdu, request_pdu_len};
+
+// ':' + 2 * (address + 253 byte PDU + LRC) + CR LF
+const MAX_FRAME_LEN: usize = 513;
+
+#[derive(Default)]
+pub struct ModbusASCIICodec {
+    diagnostics: Arc<Diagnostics>
+}
+
+impl ModbusASCIICodec {
+    pub fn new(diagnostics: Arc<Diagnostics>) -> ModbusASCIICodec {
+        ModbusASCIICodec { diagnostics: diagnostics }
+    }
+}
+
+#[derive(Debug)]
+pub struct ModbusASCIIRequest {
+    pub slave: u8,
+    pub pdu: ModbusRequestPDU
+}
+
+#[derive(Debug)]
+pub struct ModbusASCIIResponse {
+    pub slave: u8,
+    pub pdu: ModbusResponsePDU
+}
+
+// Two's complement of the 8 bit sum of all bytes, so that the sum of the
+// message and its LRC is zero.
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'...b'9' => Some(c - b'0'),
+        b'A'...b'F' => Some(c - b'A' + 10),
+        b'a'...b'f' => Some(c - b'a' + 10),
+        _ => None
+    }
+}
+
+fn hex_decode(from: &[u8]) -> Option<Vec<u8>> {
+    if from.len() % 2 != 0 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(from.len() / 2);
+    for pair in from.chunks(2) {
+        match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(hi), Some(lo)) => bytes.push(hi << 4 | lo),
+            _ => return None
+        }
+    }
+    Some(bytes)
+}
+
+fn hex_encode(from: &[u8], into: &mut Vec<u8>) {
+    const DIGITS: &'static [u8] = b"0123456789ABCDEF";
+    for b in from {
+        into.push(DIGITS[(b >> 4) as usize]);
+        into.push(DIGITS[(b & 0x0f) as usize]);
+    }
+}
+
+// Decode the characters between ':' and CR LF. Anything that does not
+// hold exactly one well-formed request with a valid LRC yields None; an
+// unknown function code is passed on for the block to reject.
+fn parse_line(line: &[u8]) -> Option<ModbusASCIIRequest> {
+    let bytes = match hex_decode(line) {
+        Some(bytes) => bytes,
+        None => return None
+    };
+    // address, function code and LRC at the very least.
+    if bytes.len() < 3 || lrc(&bytes[..bytes.len() - 1]) != bytes[bytes.len() - 1] {
+        return None;
+    }
+    let pdu = &bytes[1..bytes.len() - 1];
+    match request_pdu_len(pdu) {
+        Ok(Some(length)) if length == pdu.len() => {},
+        Err(()) => {},
+        _ => return None
+    }
+    parse_modbus_request_pdu(pdu).ok().map(|pdu| ModbusASCIIRequest {
+        slave: bytes[0],
+        pdu: pdu
+    })
+}
+
+impl Codec for ModbusASCIICodec {
+    type In = ModbusASCIIRequest;
+    type Out = ModbusASCIIResponse;
+
+    // Partial lines stay in the buffer until their CR LF arrives. A ':'
+    // always starts a new frame, so noise before it and malformed lines are
+    // discarded without answering, as a slave on a shared line should.
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        loop {
+            let start = buf.as_slice().iter().position(|&c| c == b':');
+            match start {
+                Some(start) => { buf.drain_to(start); },
+                None => {
+                    let len = buf.len();
+                    buf.drain_to(len);
+                    return Ok(None);
+                }
+            }
+            let end = buf.as_slice().windows(2).position(|w| w == b"\r\n");
+            let end = match end {
+                Some(end) => end,
+                None => {
+                    if buf.len() > MAX_FRAME_LEN {
+                        buf.drain_to(1);
+                        continue;
+                    }
+                    return Ok(None);
+                }
+            };
+            // A ':' inside the line means the previous frame was cut short.
+            let restart = buf.as_slice()[..end].iter().rposition(|&c| c == b':').unwrap_or(0);
+            buf.drain_to(restart);
+            let line = buf.drain_to(end + 2 - restart);
+            let s = line.as_slice();
+            match parse_line(&s[1..s.len() - 2]) {
+                Some(req) => {
+                    self.diagnostics.count_bus_message();
+                    return Ok(Some(req));
+                },
+                None => self.diagnostics.count_bus_communication_error()
+            }
+        }
+    }
+
+    // Broadcasts (slave 0) are carried out but never answered.
+    fn encode(&mut self, item: ModbusASCIIResponse, into: &mut Vec<u8>) -> io::Result<()> {
+        if item.slave == 0 {
+            return Ok(());
+        }
+        let mut bytes: Vec<u8> = vec![item.slave];
+        bytes.write(item.pdu.encode().as_slice())?;
+        let check = lrc(&bytes);
+        bytes.push(check);
+        into.push(b':');
+        hex_encode(&bytes, into);
+        into.write(b"\r\n")?;
+        Ok(())
+    }
+}
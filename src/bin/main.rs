@@ -10,52 +10,80 @@ extern crate modbus_server;
 extern crate futures;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_core;
 
 extern crate docopt;
 extern crate rustc_serialize;
 
 use std::sync::{Arc,Mutex};
+use std::collections::HashMap;
 use std::str;
 use futures::{future, Future, BoxFuture,Stream,Sink};
 use std::thread;
+use std::time::Duration;
 use std::sync::mpsc::channel;
 use docopt::Docopt;
 use std::io::{self, ErrorKind, Write,Read};
-use tokio_proto::TcpServer;
+use std::net::SocketAddr;
 use tokio_service::Service;
+use tokio_core::io::Io;
+use tokio_core::reactor::Core;
+use tokio_core::net::{TcpListener, UdpSocket};
     
-use modbus_server::{ModbusTCPProto,ModbusTCPResponse,ModbusTCPRequest};
+use modbus_server::{ModbusTCPCodec,ModbusTCPResponse,ModbusTCPRequest};
+use modbus_server::serve_udp;
 
 const USAGE: &'static str = "
 Usage: slave [options] <resources> ...
 
 Options:
     --addr=<addr>  # Base URL  [default: 127.0.0.1:502].
+    --udp=<addr>   # Also serve Modbus/UDP on this address.
+    --config=<file>  # Build the units from this JSON register map.
+    --state=<file>   # Restore the registers from this file and keep it saved.
+                     # With --config, each unit has its own file, named
+                     # after this one with the unit id added, as in state.1.
+    --save-every=<secs>  # Save the state this often rather than on each write.
 ";
 
+// How often simulated values are updated.
+const SIMULATION_TICK_MS: u64 = 100;
+
 #[derive(Debug, RustcDecodable)]
 struct Args {
     arg_resource: Vec<String>,
-    flag_addr: String
+    flag_addr: String,
+    flag_udp: Option<String>,
+    flag_config: Option<String>,
+    flag_state: Option<String>,
+    flag_save_every: Option<u64>
 }
 
-// TODO: add ModbusRTUCodec
-
-use modbus_server::BlankRegisters;
-
-pub struct ModbusService {
-    block:Arc<Mutex<BlankRegisters>>
+use modbus_server::{BlankRegisters, ModbusBlock, Config, Diagnostics};
+use modbus_server::{RegistersHandle, SavePolicy, Snapshot, SnapshotError};
+use modbus_server::snapshot;
+use modbus_server::ModbusResponsePDU;
+
+// Without a configuration there is a single block, answering every unit
+// id; with one, only the unit ids it lists. There is a service for each
+// master, so that blocks can tell who wrote to them.
+pub struct ModbusService<B> {
+    blocks:Arc<HashMap<u8,Arc<Mutex<B>>>>,
+    default:Option<Arc<Mutex<B>>>,
+    peer:Option<SocketAddr>
 }
 
-impl ModbusService {
+impl<B: ModbusBlock> ModbusService<B> {
     fn new (
-        block:Arc<Mutex<BlankRegisters>>)->ModbusService {
-        ModbusService{ block:block}
+        blocks:Arc<HashMap<u8,Arc<Mutex<B>>>>,
+        default:Option<Arc<Mutex<B>>>,
+        peer:Option<SocketAddr>)->ModbusService<B> {
+        ModbusService{ blocks:blocks, default:default, peer:peer}
     }
     
 }
 
-impl Service for ModbusService {
+impl<B: ModbusBlock> Service for ModbusService<B> {
     
     type Request = ModbusTCPRequest;
     type Response = ModbusTCPResponse;    
@@ -63,24 +91,120 @@ impl Service for ModbusService {
     type Future = future::FutureResult<Self::Response, Self::Error>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let mut a = self.block.lock().unwrap();
+        let pdu = match self.blocks.get(&req.header.uid).or(self.default.as_ref()) {
+            Some(block) => block.lock().unwrap().call_from(self.peer, req.header.uid, req.pdu),
+            // Gateway Target Device Failed to Respond.
+            None => ModbusResponsePDU::ModbusErrorResponse{
+                code:req.pdu.code() | 0x80,
+                exception_code:0x0B
+            }
+        };
         future::finished(Self::Response {
             header:req.header,
-            pdu:
-            a.call(req.pdu)
+            pdu:pdu
         })
     }
 }
 
 fn main() {
-    let mut block = Arc::new(Mutex::new(BlankRegisters::new()));
-
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| {println!("DAMN {:?}",e); e.exit()});
     println!("{:?}", args);
-    
-    TcpServer::new(ModbusTCPProto, args.flag_addr.parse().unwrap())
-        .serve(move || Ok(ModbusService::new(block.clone())));
-}
 
+    // One set of counters for the whole port.
+    let diagnostics = Arc::new(Diagnostics::new());
+    let mut blocks = HashMap::new();
+    let mut default = None;
+    let mut simulations = Vec::new();
+    match args.flag_config {
+        Some(ref path) => {
+            let config = Config::load(path).unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(1)
+            });
+            for unit in config.units {
+                let mut registers = unit.build();
+                registers.set_diagnostics(diagnostics.clone());
+                let block = Arc::new(Mutex::new(registers));
+                if !unit.simulation.is_empty() {
+                    simulations.push((unit.simulation, block.clone()));
+                }
+                blocks.insert(unit.unit_id, block);
+            }
+        },
+        None => {
+            let mut registers = BlankRegisters::new();
+            registers.set_diagnostics(diagnostics.clone());
+            default = Some(Arc::new(Mutex::new(registers)));
+        }
+    }
+
+    if let Some(ref state) = args.flag_state {
+        let policy = match args.flag_save_every {
+            Some(0) => {
+                println!("--save-every must be at least 1 second");
+                std::process::exit(1)
+            },
+            Some(secs) => SavePolicy::Every(Duration::from_secs(secs)),
+            None => SavePolicy::OnWrite
+        };
+        let mut states: Vec<(String, Arc<Mutex<BlankRegisters>>)> = blocks.iter()
+            .map(|(unit_id, block)| (format!("{}.{}", state, unit_id), block.clone()))
+            .collect();
+        if let Some(ref block) = default {
+            states.push((state.clone(), block.clone()));
+        }
+        for (path, block) in states {
+            match Snapshot::load(&path).and_then(|snapshot| block.lock().unwrap().restore(&snapshot)) {
+                Ok(()) => {},
+                // Nothing saved yet.
+                Err(SnapshotError::Io(ref e)) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => {
+                    println!("{}: {}", path, e);
+                    std::process::exit(1)
+                }
+            }
+            let handle = RegistersHandle::from_shared(block);
+            thread::spawn(move || {
+                if let Err(e) = snapshot::keep_saved(handle, &path, policy) {
+                    println!("can't save {}: {}", path, e);
+                }
+            });
+        }
+    }
+
+    // Started after any saved state is restored, which would otherwise
+    // overwrite the first simulated values.
+    for (simulation, block) in simulations {
+        let handle = RegistersHandle::from_shared(block);
+        thread::spawn(move || simulation.run(handle, Duration::from_millis(SIMULATION_TICK_MS)));
+    }
+
+    let blocks = Arc::new(blocks);
+    if let Some(udp_addr) = args.flag_udp.clone() {
+        let blocks = blocks.clone();
+        let default = default.clone();
+        let diagnostics = diagnostics.clone();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let socket = UdpSocket::bind(&udp_addr.parse().unwrap(), &core.handle()).unwrap();
+            let new_service = move |peer| ModbusService::new(blocks.clone(), default.clone(), Some(peer));
+            core.run(serve_udp(socket, new_service, diagnostics)).unwrap();
+        });
+    }
+
+    // Connections are accepted here rather than by a TcpServer, which
+    // doesn't tell the service who the master is.
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&args.flag_addr.parse().unwrap(), &handle).unwrap();
+    let connections = listener.incoming().for_each(move |(socket, peer)| {
+        let (responses, requests) = socket.framed(ModbusTCPCodec::new(diagnostics.clone())).split();
+        let service = ModbusService::new(blocks.clone(), default.clone(), Some(peer));
+        let answered = requests.and_then(move |req| service.call(req));
+        handle.spawn(responses.send_all(answered).then(|_| Ok(())));
+        Ok(())
+    });
+    core.run(connections).unwrap();
+}
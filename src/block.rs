@@ -4,13 +4,373 @@ use modbus::{Coil,binary,Reason,ExceptionCode,tcp};
 use {Code, Count, Address, Values, Value, Quantity};
 use {ModbusResponsePDU, ModbusRequestPDU}; 
 use FunctionCode;
+use identification::{self, DeviceIdentification};
+use diagnostics::{self, Diagnostics};
+use events::{self, CommEventLog};
+use files::FileRecords;
+use values::{self, RegisterOrder, RegisterValue};
+use access::{Access, AccessRules};
+use hooks::{Hooks, WriteResult};
+use changes::{self, Change, ChangeNotifier};
+use snapshot::{Snapshot, SnapshotError};
+use futures::sync::mpsc::UnboundedReceiver;
+use Table;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
 use enum_primitive::FromPrimitive;
 
+// The most entries Read FIFO Queue can return.
+const MAX_FIFO_COUNT: usize = 31;
+
+// A Modbus server's data model, one per unit id. Each function has its own
+// method, called with the request already decoded, and any function not
+// implemented answers IllegalFunction. `call` is what the services use; it
+// can be overridden to wrap bookkeeping around `dispatch`.
+pub trait ModbusBlock {
+
+    fn call(&mut self, req: ModbusRequestPDU) -> ModbusResponsePDU {
+        self.dispatch(req)
+    }
+
+    // `call` for services that know which unit id the request was sent to
+    // and, if they can tell, which master sent it.
+    fn call_from(&mut self, _peer: Option<SocketAddr>, _unit_id: u8, req: ModbusRequestPDU) -> ModbusResponsePDU {
+        self.call(req)
+    }
+
+    fn read_coils(&mut self, code: Code, _address: Address, _quantity: Quantity) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_discrete_inputs(&mut self, code: Code, _address: Address, _quantity: Quantity) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_holding_registers(&mut self, code: Code, _address: Address, _quantity: Quantity) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_input_registers(&mut self, code: Code, _address: Address, _quantity: Quantity) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    // `value` is 0xFF00 or 0x0000 for on and off; anything else is for the
+    // implementation to reject.
+    fn write_single_coil(&mut self, code: Code, _address: Address, _value: Value) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn write_single_register(&mut self, code: Code, _address: Address, _value: Value) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn write_multiple_coils(&mut self, code: Code, _address: Address, _quantity: Quantity,
+                            _values: Vec<modbus::Coil>) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn write_multiple_registers(&mut self, code: Code, _address: Address, _quantity: Quantity,
+                                _values: Values) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_write_multiple_registers(&mut self, code: Code,
+                                     _read_address: Address, _read_quantity: Quantity,
+                                     _write_address: Address, _write_quantity: Quantity,
+                                     _values: Values) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn mask_write_register(&mut self, code: Code, _address: Address,
+                           _and_mask: Value, _or_mask: Value) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_exception_status(&mut self, code: Code) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn diagnostic(&mut self, code: Code, _sub_function: u16, _data: Vec<u8>) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn get_comm_event_counter(&mut self, code: Code) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn get_comm_event_log(&mut self, code: Code) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn report_server_id(&mut self, code: Code) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    // `data` is the sub-requests, still in wire format.
+    fn read_file_record(&mut self, code: Code, _data: &[u8]) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn write_file_record(&mut self, code: Code, _data: &[u8]) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_fifo_queue(&mut self, code: Code, _address: Address) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    fn read_device_identification(&mut self, code: Code, _read_code: u8, _object_id: u8) -> ModbusResponsePDU {
+        illegal_function(code)
+    }
+
+    // Decodes the request's data for its function and hands it to that
+    // function's method. Data that doesn't match the request is rejected
+    // here, before any method sees it.
+    fn dispatch(&mut self, req: ModbusRequestPDU) -> ModbusResponsePDU {
+        let function = match FunctionCode::from_u8(req.code) {
+            Some(function) => function,
+            None => return illegal_function(req.code)
+        };
+        match function {
+            FunctionCode::WriteMultipleCoils  => {
+                let values = match req.addl {
+                    // The byte count must be just enough for the coils.
+                    Some(ref addl) if addl.data.len() == (req.q_or_v as usize + 7) / 8 => {
+                        binary::unpack_bits(&addl.data, req.q_or_v)
+                    },
+                    _ => return illegal_data_value(req.code)
+                };
+                self.write_multiple_coils(req.code, req.address, req.q_or_v, values)
+            },
+            FunctionCode::WriteMultipleRegisters  => {
+                let values = match req.addl.map(|addl| binary::pack_bytes(&addl.data)) {
+                    Some(Ok(values)) => values,
+                    _ => return illegal_data_value(req.code)
+                };
+                if values.len() != req.q_or_v as usize {
+                    return illegal_data_value(req.code);
+                }
+                self.write_multiple_registers(req.code, req.address, req.q_or_v, values)
+            },
+            FunctionCode::ReadWriteMultipleRegisters  => {
+                // write address, write quantity, then the values.
+                let words = match req.addl.map(|addl| binary::pack_bytes(&addl.data)) {
+                    Some(Ok(words)) => words,
+                    _ => return illegal_data_value(req.code)
+                };
+                if words.len() < 2 {
+                    return illegal_data_value(req.code);
+                }
+                self.read_write_multiple_registers(
+                    req.code, req.address, req.q_or_v, words[0], words[1], words[2..].to_vec())
+            },
+            FunctionCode::MaskWriteRegister  => {
+                let or_mask = match req.addl.map(|addl| binary::pack_bytes(&addl.data)) {
+                    Some(Ok(ref words)) if words.len() == 1 => words[0],
+                    _ => return illegal_data_value(req.code)
+                };
+                self.mask_write_register(req.code, req.address, req.q_or_v, or_mask)
+            },
+            FunctionCode::EncapsulatedInterfaceTransport  => {
+                let data = match req.addl {
+                    Some(addl) => addl.data,
+                    None => return illegal_data_value(req.code)
+                };
+                match (data.first(), data.len()) {
+                    (Some(&identification::MEI_READ_DEVICE_ID), 3) => {
+                        self.read_device_identification(req.code, data[1], data[2])
+                    },
+                    (Some(&identification::MEI_READ_DEVICE_ID), _) |
+                    (None, _) => illegal_data_value(req.code),
+                    // No other MEI types are supported.
+                    (Some(_), _) => illegal_function(req.code)
+                }
+            },
+            FunctionCode::Diagnostics  => {
+                let data = req.addl.map(|addl| addl.data).unwrap_or(Vec::new());
+                self.diagnostic(req.code, req.address, data)
+            },
+            FunctionCode::ReadFileRecord  => {
+                match req.addl {
+                    Some(addl) => self.read_file_record(req.code, &addl.data),
+                    None => illegal_data_value(req.code)
+                }
+            },
+            FunctionCode::WriteFileRecord  => {
+                match req.addl {
+                    Some(addl) => self.write_file_record(req.code, &addl.data),
+                    None => illegal_data_value(req.code)
+                }
+            },
+            FunctionCode::ReadFIFOQueue => self.read_fifo_queue(req.code, req.address),
+            FunctionCode::ReadExceptionStatus => self.read_exception_status(req.code),
+            FunctionCode::GetCommEventCounter => self.get_comm_event_counter(req.code),
+            FunctionCode::GetCommEventLog => self.get_comm_event_log(req.code),
+            FunctionCode::ReportServerId => self.report_server_id(req.code),
+            FunctionCode::WriteSingleCoil => self.write_single_coil(req.code, req.address, req.q_or_v),
+            FunctionCode::WriteSingleRegister => self.write_single_register(req.code, req.address, req.q_or_v),
+            FunctionCode::ReadHoldingRegisters => self.read_holding_registers(req.code, req.address, req.q_or_v),
+            FunctionCode::ReadInputRegisters => self.read_input_registers(req.code, req.address, req.q_or_v),
+            FunctionCode::ReadCoils => self.read_coils(req.code, req.address, req.q_or_v),
+            FunctionCode::ReadDiscreteInputs => self.read_discrete_inputs(req.code, req.address, req.q_or_v)
+        }
+    }
+}
+
+// One of the four tables, covering `values.len()` addresses from `start`.
+#[derive(Debug,Clone)]
+struct Store<T> {
+    start : Address,
+    values : Vec<T>
+}
+
+impl<T: Clone> Store<T> {
+    // Cut short if it would run past the last address.
+    fn new(start: Address, size: usize, value: T) -> Store<T> {
+        let size = ::std::cmp::min(size, 0x10000 - start as usize);
+        Store { start: start, values: vec![value; size] }
+    }
+
+    fn get(&self, address: Address, quantity: Quantity) -> Option<Vec<T>> {
+        self.offset(address, quantity).map(|offset| self.values[offset..offset + quantity as usize].to_vec())
+    }
+
+    fn set(&mut self, address: Address, values: &[T]) -> bool {
+        // All 65536 addresses can be set at once, which is more than a
+        // Quantity can count.
+        match self.offset_of_len(address, values.len()) {
+            Some(offset) => {
+                self.values[offset..offset + values.len()].clone_from_slice(values);
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Where `quantity` entries from `address` begin in `values`, if the
+    // table holds all of them.
+    fn offset(&self, address: Address, quantity: Quantity) -> Option<usize> {
+        self.offset_of_len(address, quantity as usize)
+    }
+
+    fn offset_of_len(&self, address: Address, len: usize) -> Option<usize> {
+        let start = self.start as usize;
+        let address = address as usize;
+        if address < start || address + len > start + self.values.len() {
+            None
+        } else {
+            Some(address - start)
+        }
+    }
+}
+
+// The start address and size of each table for a BlankRegisters. By
+// default every table covers the whole address space. Addresses are the
+// ones on the wire, counting from 0: holding register 40001 in the
+// traditional numbering is address 0.
+#[derive(Debug,Clone)]
+pub struct BlankRegistersBuilder {
+    coils : (Address, usize),
+    discrete_inputs : (Address, usize),
+    holding_registers : (Address, usize),
+    input_registers : (Address, usize),
+    read_only : AccessRules
+}
+
+impl Default for BlankRegistersBuilder {
+    fn default() -> BlankRegistersBuilder {
+        BlankRegistersBuilder {
+            coils: (0, 0x10000),
+            discrete_inputs: (0, 0x10000),
+            holding_registers: (0, 0x10000),
+            input_registers: (0, 0x10000),
+            read_only: AccessRules::new()
+        }
+    }
+}
+
+impl BlankRegistersBuilder {
+
+    pub fn new() -> BlankRegistersBuilder {
+        BlankRegistersBuilder::default()
+    }
+
+    pub fn coils(mut self, start: Address, size: usize) -> BlankRegistersBuilder {
+        self.coils = (start, size);
+        self
+    }
+
+    pub fn discrete_inputs(mut self, start: Address, size: usize) -> BlankRegistersBuilder {
+        self.discrete_inputs = (start, size);
+        self
+    }
+
+    pub fn holding_registers(mut self, start: Address, size: usize) -> BlankRegistersBuilder {
+        self.holding_registers = (start, size);
+        self
+    }
+
+    pub fn input_registers(mut self, start: Address, size: usize) -> BlankRegistersBuilder {
+        self.input_registers = (start, size);
+        self
+    }
+
+    // Writes from masters to a read-only table fail with
+    // IllegalDataAddress, whatever set_access_rules says later.
+    pub fn read_only_coils(mut self) -> BlankRegistersBuilder {
+        self.read_only.add(Table::Coils, 0, 0x10000, Access::ReadOnly);
+        self
+    }
+
+    pub fn read_only_holding_registers(mut self) -> BlankRegistersBuilder {
+        self.read_only.add(Table::HoldingRegisters, 0, 0x10000, Access::ReadOnly);
+        self
+    }
+
+    pub fn build(self) -> BlankRegisters {
+        BlankRegisters {
+            holding_registers: Store::new(self.holding_registers.0, self.holding_registers.1, 0),
+            coils: Store::new(self.coils.0, self.coils.1, modbus::Coil::Off),
+            input_registers: Store::new(self.input_registers.0, self.input_registers.1, 0),
+            discrete_registers: Store::new(self.discrete_inputs.0, self.discrete_inputs.1, modbus::Coil::Off),
+            read_only:self.read_only,
+            access:AccessRules::new(),
+            coil_hooks:Hooks::new(),
+            register_hooks:Hooks::new(),
+            changes:ChangeNotifier::new(),
+            identification:DeviceIdentification::default(),
+            diagnostics:Arc::new(Diagnostics::new()),
+            events:CommEventLog::new(),
+            exception_status:0,
+            server_id:Vec::new(),
+            run_indicator:true,
+            fifos:HashMap::new(),
+            files:FileRecords::new()
+        }
+    }
+}
+
 pub struct BlankRegisters {
-    holding_registers : Vec<u16>,
-    input_registers : Vec<u16>,
-    coils : Vec<modbus::Coil>,
-    discrete_registers : Vec<modbus::Coil>    
+    holding_registers : Store<u16>,
+    input_registers : Store<u16>,
+    coils : Store<modbus::Coil>,
+    discrete_registers : Store<modbus::Coil>,
+    // The builder's read-only tables, kept apart from the access rules so
+    // that setting those doesn't undo them.
+    read_only : AccessRules,
+    access : AccessRules,
+    coil_hooks : Hooks<modbus::Coil>,
+    register_hooks : Hooks<Value>,
+    changes : ChangeNotifier,
+    identification : DeviceIdentification,
+    diagnostics : Arc<Diagnostics>,
+    events : CommEventLog,
+    exception_status : u8,
+    server_id : Vec<u8>,
+    run_indicator : bool,
+    fifos : HashMap<Address, VecDeque<Value>>,
+    files : FileRecords
 }
 
 impl  BlankRegisters {
@@ -19,39 +379,347 @@ impl  BlankRegisters {
     // a data store for each category.
     
     pub fn new () -> BlankRegisters {
-        let  holding_registers = vec![0;65536];
-        let  coils = vec![modbus::Coil::Off;65536];
-        let  discrete_registers = vec![modbus::Coil::Off;65536];
-        let  input_registers = vec![0;65536];
-        BlankRegisters {
-            holding_registers: holding_registers,
-            coils:coils,
-            input_registers:input_registers,
-            discrete_registers:discrete_registers                
-        }        
+        BlankRegistersBuilder::default().build()
+    }
+
+    // For tables smaller than the whole address space, or not starting at
+    // address 0.
+    pub fn builder() -> BlankRegistersBuilder {
+        BlankRegistersBuilder::new()
+    }
+
+    // The table setters write from `address` on, and return false without
+    // writing anything if the table doesn't hold all of `values`.
+    pub fn set_coils(&mut self, address: Address, values: &[modbus::Coil]) -> bool {
+        self.coils.set(address, values)
+    }
+
+    pub fn set_discrete_inputs(&mut self, address: Address, values: &[modbus::Coil]) -> bool {
+        self.discrete_registers.set(address, values)
+    }
+
+    pub fn set_holding_registers(&mut self, address: Address, values: &[Value]) -> bool {
+        self.holding_registers.set(address, values)
+    }
+
+    pub fn set_input_registers(&mut self, address: Address, values: &[Value]) -> bool {
+        self.input_registers.set(address, values)
+    }
+
+    // The getters return None if the table doesn't hold all `quantity`
+    // entries from `address`.
+    pub fn coils(&self, address: Address, quantity: Quantity) -> Option<Vec<modbus::Coil>> {
+        self.coils.get(address, quantity)
+    }
+
+    pub fn discrete_inputs(&self, address: Address, quantity: Quantity) -> Option<Vec<modbus::Coil>> {
+        self.discrete_registers.get(address, quantity)
+    }
+
+    pub fn holding_registers(&self, address: Address, quantity: Quantity) -> Option<Values> {
+        self.holding_registers.get(address, quantity)
+    }
+
+    pub fn input_registers(&self, address: Address, quantity: Quantity) -> Option<Values> {
+        self.input_registers.get(address, quantity)
+    }
+
+    // Typed values spread over consecutive registers from `address`; see
+    // values.rs for the orders.
+    pub fn holding_value<T: RegisterValue>(&self, address: Address, order: RegisterOrder) -> Option<T> {
+        self.holding_registers(address, T::register_count())
+            .and_then(|registers| T::from_registers(&registers, order))
+    }
+
+    pub fn set_holding_value<T: RegisterValue>(&mut self, address: Address, value: T, order: RegisterOrder) -> bool {
+        self.set_holding_registers(address, &value.to_registers(order))
+    }
+
+    pub fn input_value<T: RegisterValue>(&self, address: Address, order: RegisterOrder) -> Option<T> {
+        self.input_registers(address, T::register_count())
+            .and_then(|registers| T::from_registers(&registers, order))
+    }
+
+    pub fn set_input_value<T: RegisterValue>(&mut self, address: Address, value: T, order: RegisterOrder) -> bool {
+        self.set_input_registers(address, &value.to_registers(order))
+    }
+
+    // ASCII strings taking up `count` registers.
+    pub fn holding_string(&self, address: Address, count: Quantity, order: RegisterOrder) -> Option<String> {
+        self.holding_registers(address, count)
+            .map(|registers| values::string_from_registers(&registers, order))
+    }
+
+    pub fn set_holding_string(&mut self, address: Address, value: &str, count: Quantity, order: RegisterOrder) -> bool {
+        match values::string_to_registers(value, count, order) {
+            Some(registers) => self.set_holding_registers(address, &registers),
+            None => false
+        }
+    }
+
+    pub fn input_string(&self, address: Address, count: Quantity, order: RegisterOrder) -> Option<String> {
+        self.input_registers(address, count)
+            .map(|registers| values::string_from_registers(&registers, order))
+    }
+
+    pub fn set_input_string(&mut self, address: Address, value: &str, count: Quantity, order: RegisterOrder) -> bool {
+        match values::string_to_registers(value, count, order) {
+            Some(registers) => self.set_input_registers(address, &registers),
+            None => false
+        }
+    }
+
+    // Which addresses masters may read and write. The application's own
+    // access through the setters above isn't restricted.
+    pub fn set_access_rules(&mut self, access: AccessRules) {
+        self.access = access;
+    }
+
+    // Run `hook` on writes by masters to any of `size` coils from `start`;
+    // see hooks.rs.
+    pub fn on_coil_write<F>(&mut self, start: Address, size: usize, hook: F)
+        where F: FnMut(Address, &mut [modbus::Coil]) -> WriteResult + Send + 'static
+    {
+        self.coil_hooks.add(start, size, hook);
+    }
+
+    // The same for holding registers, including those written by Mask
+    // Write Register and Read/Write Multiple Registers.
+    pub fn on_register_write<F>(&mut self, start: Address, size: usize, hook: F)
+        where F: FnMut(Address, &mut [Value]) -> WriteResult + Send + 'static
+    {
+        self.register_hooks.add(start, size, hook);
+    }
+
+    // The contents of the four tables, to save and later restore.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            coils: (self.coils.start, self.coils.values.clone()),
+            discrete_inputs: (self.discrete_registers.start, self.discrete_registers.values.clone()),
+            holding_registers: (self.holding_registers.start, self.holding_registers.values.clone()),
+            input_registers: (self.input_registers.start, self.input_registers.values.clone())
+        }
+    }
+
+    // Changes nothing unless every table in the snapshot has the same
+    // first address and size as the block's. Hooks don't run and no
+    // changes are sent.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        fn fits<T, U>(store: &Store<T>, &(start, ref values): &(Address, Vec<U>)) -> bool {
+            store.start == start && store.values.len() == values.len()
+        }
+        if !fits(&self.coils, &snapshot.coils) ||
+            !fits(&self.discrete_registers, &snapshot.discrete_inputs) ||
+            !fits(&self.holding_registers, &snapshot.holding_registers) ||
+            !fits(&self.input_registers, &snapshot.input_registers) {
+            return Err(SnapshotError::Mismatch);
+        }
+        self.coils.values.clone_from_slice(&snapshot.coils.1);
+        self.discrete_registers.values.clone_from_slice(&snapshot.discrete_inputs.1);
+        self.holding_registers.values.clone_from_slice(&snapshot.holding_registers.1);
+        self.input_registers.values.clone_from_slice(&snapshot.input_registers.1);
+        Ok(())
+    }
+
+    // Changes made by masters to coils and holding registers; see
+    // changes.rs.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<Change> {
+        self.changes.subscribe()
+    }
+
+    // Reported with every change.
+    pub fn set_unit_id(&mut self, unit_id: u8) {
+        self.changes.set_unit_id(unit_id);
+    }
+
+    // The counters answered by Diagnostics (FC 08), to be handed to the
+    // codec or proto serving this block so they can count bus traffic.
+    pub fn diagnostics(&self) -> Arc<Diagnostics> {
+        self.diagnostics.clone()
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Arc<Diagnostics>) {
+        self.diagnostics = diagnostics;
+    }
+
+    // The objects returned by Read Device Identification for this unit.
+    pub fn set_device_identification(&mut self, identification: DeviceIdentification) {
+        self.identification = identification;
+    }
+
+    // The eight exception status outputs returned by Read Exception Status
+    // (FC 07).
+    pub fn set_exception_status(&mut self, status: u8) {
+        self.exception_status = status;
+    }
+
+    // The device specific id and the run indicator returned by Report
+    // Server ID (FC 17).
+    pub fn set_server_id<T: Into<Vec<u8>>>(&mut self, server_id: T) {
+        self.server_id = server_id.into();
+        // The byte count has to cover the id and the run indicator.
+        self.server_id.truncate(250);
+    }
+
+    pub fn set_run_indicator(&mut self, on: bool) {
+        self.run_indicator = on;
+    }
+
+    // Make `address` the pointer address of a FIFO queue read with Read
+    // FIFO Queue (FC 24). As on most devices, the holding register at that
+    // address holds the number of entries in the queue.
+    pub fn attach_fifo(&mut self, address: Address) {
+        self.fifos.entry(address).or_insert(VecDeque::new());
+        self.sync_fifo_count(address);
+    }
+
+    // Returns false if no queue is attached at `address`. The queue may
+    // grow past the 31 entries a master can read, in which case reads of
+    // it fail with IllegalDataValue until the application drains it.
+    pub fn push_fifo(&mut self, address: Address, value: Value) -> bool {
+        match self.fifos.get_mut(&address) {
+            Some(fifo) => fifo.push_back(value),
+            None => return false
+        }
+        self.sync_fifo_count(address);
+        true
+    }
+
+    // Reading the queue doesn't consume it; that is up to the application.
+    pub fn pop_fifo(&mut self, address: Address) -> Option<Value> {
+        let value = self.fifos.get_mut(&address).and_then(|fifo| fifo.pop_front());
+        self.sync_fifo_count(address);
+        value
+    }
+
+    pub fn clear_fifo(&mut self, address: Address) {
+        if let Some(fifo) = self.fifos.get_mut(&address) {
+            fifo.clear();
+        }
+        self.sync_fifo_count(address);
+    }
+
+    // A file read and written with Read and Write File Record (FC 20 and
+    // 21). Masters can't change its length, only its records.
+    pub fn set_file(&mut self, file_number: u16, records: Values) {
+        self.files.set_file(file_number, records);
+    }
+
+    pub fn file(&self, file_number: u16) -> Option<&[Value]> {
+        self.files.file(file_number)
+    }
+
+    pub fn remove_file(&mut self, file_number: u16) {
+        self.files.remove_file(file_number);
+    }
+
+    fn sync_fifo_count(&mut self, address: Address) {
+        if let (Some(fifo), Some(offset)) = (self.fifos.get(&address),
+                                             self.holding_registers.offset(address, 1)) {
+            self.holding_registers.values[offset] = fifo.len() as u16;
+        }
+    }
+    // Keeps the diagnostics counters and the comm event log up to date
+    // around the dispatch.
+    fn serve(&mut self, req: ModbusRequestPDU, broadcast: bool) -> ModbusResponsePDU {
+        println!("BR call");
+        self.diagnostics.count_server_message();
+        self.events.push(events::receive_event(broadcast));
+        let resp = match self.read_only.check(&req).or_else(|| self.access.check(&req)) {
+            Some(denied) => denied,
+            None => self.dispatch(req)
+        };
+        if broadcast {
+            self.diagnostics.count_server_no_response();
+        }
+        if let ModbusResponsePDU::ModbusErrorResponse{exception_code:e, ..} = resp {
+            // A broadcast's exception is never sent.
+            if !broadcast {
+                self.diagnostics.count_bus_exception_error();
+                self.events.push(events::send_event(Some(e)));
+            }
+            return resp;
+        }
+        if !broadcast {
+            self.events.push(events::send_event(None));
+        }
+        // Fetching the counter or the log doesn't count as an event, and
+        // neither does the request that has just cleared the counter.
+        let counted = match resp {
+            ModbusResponsePDU::GetCommEventCounterResponse{..} |
+            ModbusResponsePDU::GetCommEventLogResponse{..} => false,
+            ModbusResponsePDU::DiagnosticsResponse{sub_function:f, ..} => {
+                f != diagnostics::RESTART_COMMUNICATIONS_OPTION &&
+                    f != diagnostics::CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER
+            },
+            _ => true
+        };
+        if counted {
+            self.events.count_event();
+        }
+        resp
+    }
+}
+
+impl ModbusBlock for BlankRegisters {
+
+    // Changes are reported as coming from `peer` and `unit_id`. Unit 0 is
+    // a broadcast, which is never answered.
+    fn call_from(&mut self, peer: Option<SocketAddr>, unit_id: u8, req: ModbusRequestPDU) -> ModbusResponsePDU {
+        self.changes.set_source(peer, unit_id);
+        let resp = self.serve(req, unit_id == 0);
+        self.changes.clear_source();
+        resp
+    }
+
+    fn call(& mut self, req: ModbusRequestPDU) -> ModbusResponsePDU {
+        self.serve(req, false)
+    }
+
+    fn read_fifo_queue (&mut self,code:Code, address:Address) ->ModbusResponsePDU {
+        match self.fifos.get(&address) {
+            None => ModbusResponsePDU::ModbusErrorResponse{
+                code:0x98,
+                exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
+            },
+            Some(fifo) if fifo.len() > MAX_FIFO_COUNT => ModbusResponsePDU::ModbusErrorResponse{
+                code:0x98,
+                exception_code:modbus::ExceptionCode::IllegalDataValue as u8
+            },
+            Some(fifo) => ModbusResponsePDU::ReadFIFOQueueResponse{
+                code:code, values:fifo.iter().cloned().collect()}
+        }
     }
     
     fn write_multiple_coils(
         & mut self,
         code:Code, address:Address,
-        quantity:Quantity, values:Vec<modbus::Coil>) -> ModbusResponsePDU
+        quantity:Quantity, mut values:Vec<modbus::Coil>) -> ModbusResponsePDU
     {
-        if quantity > 0x07B0 {
-            ModbusResponsePDU::ModbusErrorResponse{
+        if quantity < 1 || quantity > 0x07B0 {
+            return ModbusResponsePDU::ModbusErrorResponse{
                 code:0x8F,
                 exception_code:modbus::ExceptionCode::IllegalDataValue as u8
-            }
-        } else if address as usize + quantity as usize > self.coils.len() {
-            ModbusResponsePDU::ModbusErrorResponse{
+            };
+        }
+        match self.coils.offset(address, quantity) {
+            Some(offset) => {
+                if let Some(refused) = self.coil_hooks.run(code, address, &mut values[..quantity as usize]) {
+                    return refused;
+                }
+                let old = changes::coil_values(&self.coils.values[offset..offset + quantity as usize]);
+                for i in 0..(quantity as usize) {
+                    self.coils.values[offset + i] = values[i] ;
+                }
+                self.changes.notify(Table::Coils, address, old,
+                                    changes::coil_values(&values[..quantity as usize]));
+                ModbusResponsePDU::WriteMultipleCoilsResponse {
+                    code: code , address:address, quantity:quantity
+                }
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
                 code:0x8F,
                 exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
-            }            
-        } else {
-            for i in 0..(quantity as usize) {
-                self.coils[ address as usize + i] = values[i] ;
-            }
-            ModbusResponsePDU::WriteMultipleCoilsResponse {
-                code: code , address:address, quantity:quantity
             }
         }
     }
@@ -59,197 +727,300 @@ impl  BlankRegisters {
     fn write_multiple_registers(
         & mut self,
         code:Code, address:Address,
-        quantity:Quantity, values:Vec<u16>) -> ModbusResponsePDU
+        quantity:Quantity, mut values:Vec<u16>) -> ModbusResponsePDU
     {
-        if quantity > 0x007B {
-            ModbusResponsePDU::ModbusErrorResponse{
+        if quantity < 1 || quantity > 0x007B {
+            return ModbusResponsePDU::ModbusErrorResponse{
                 code:0x90,
                 exception_code:modbus::ExceptionCode::IllegalDataValue as u8
-            }
-        } else if address as usize + quantity as usize > self.holding_registers.len() {
-            ModbusResponsePDU::ModbusErrorResponse{
+            };
+        }
+        match self.holding_registers.offset(address, quantity) {
+            Some(offset) => {
+                if let Some(refused) = self.register_hooks.run(code, address, &mut values[..quantity as usize]) {
+                    return refused;
+                }
+                let old = self.holding_registers.values[offset..offset + quantity as usize].to_vec();
+                for i in 0..(quantity as usize) {
+                    self.holding_registers.values[offset + i] = values[i] ;
+                }
+                self.changes.notify(Table::HoldingRegisters, address, old,
+                                    values[..quantity as usize].to_vec());
+                ModbusResponsePDU::WriteMultipleRegistersResponse {
+                    code: code , address:address, quantity:quantity
+                }
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
                 code:0x90,
                 exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
-            }            
-        } else {
-            for i in 0..quantity {
-                self.holding_registers[ (address+i) as usize ] = values[i as usize] ;
             }
-            ModbusResponsePDU::WriteMultipleRegistersResponse {
-                code: code , address:address, quantity:quantity
-            }        
         }
     }
     
-    fn write_single_coil ( &mut self,code:Code, address:Address, value:Quantity) ->ModbusResponsePDU {
-        
-        match value {
-            0xff00 => {
-                self.coils[address as usize] = modbus::Coil::On;
-                ModbusResponsePDU::WriteSingleCoilResponse {
-                    code: code , address:address, value: value
+    // The write happens before the read, so the values read back include
+    // the ones just written.
+    fn read_write_multiple_registers(
+        & mut self,
+        code:Code, read_address:Address, read_quantity:Quantity,
+        write_address:Address, write_quantity:Quantity, mut values:Vec<u16>) -> ModbusResponsePDU
+    {
+        if read_quantity < 1 || read_quantity > 0x007D ||
+            write_quantity < 1 || write_quantity > 0x0079 ||
+            values.len() != write_quantity as usize {
+            return ModbusResponsePDU::ModbusErrorResponse{
+                code:0x97,
+                exception_code:modbus::ExceptionCode::IllegalDataValue as u8
+            };
+        }
+        match (self.holding_registers.offset(read_address, read_quantity),
+               self.holding_registers.offset(write_address, write_quantity)) {
+            (Some(read_offset), Some(write_offset)) => {
+                if let Some(refused) = self.register_hooks.run(code, write_address, &mut values) {
+                    return refused;
+                }
+                let old = self.holding_registers.values[write_offset..write_offset + write_quantity as usize].to_vec();
+                for i in 0..(write_quantity as usize) {
+                    self.holding_registers.values[write_offset + i] = values[i] ;
                 }
+                self.changes.notify(Table::HoldingRegisters, write_address, old, values);
+                let read = self.holding_registers.values[read_offset..read_offset + read_quantity as usize].to_vec();
+                ModbusResponsePDU::ReadWriteMultipleRegistersResponse{
+                    code:code,byte_count: (2 * read_quantity) as u8,values:read}
             },
-            0x0000 => {
-                self.coils[address as usize] = modbus::Coil::Off;
+            _ => ModbusResponsePDU::ModbusErrorResponse{
+                code:0x97,
+                exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
+            }
+        }
+    }
+
+    // Runs under whatever lock guards the block, so masters can't race
+    // each other between the read and the write.
+    fn mask_write_register ( &mut self,code:Code, address:Address, and_mask:Value, or_mask:Value) ->ModbusResponsePDU {
+        match self.holding_registers.offset(address, 1) {
+            Some(offset) => {
+                let current = self.holding_registers.values[offset];
+                let mut value = [(current & and_mask) | (or_mask & !and_mask)];
+                if let Some(refused) = self.register_hooks.run(code, address, &mut value) {
+                    return refused;
+                }
+                self.holding_registers.values[offset] = value[0];
+                self.changes.notify(Table::HoldingRegisters, address, vec![current], value.to_vec());
+                ModbusResponsePDU::MaskWriteRegisterResponse {
+                    code: code , address:address, and_mask:and_mask, or_mask:or_mask
+                }
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
+                code:0x96,
+                exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
+            }
+        }
+    }
+
+    fn write_single_coil ( &mut self,code:Code, address:Address, value:Value) ->ModbusResponsePDU {
+        let coil = match value {
+            0xff00 => modbus::Coil::On,
+            0x0000 => modbus::Coil::Off,
+            _ => return ModbusResponsePDU::ModbusErrorResponse{
+                code:0x85,
+                exception_code:modbus::ExceptionCode::IllegalDataValue as u8
+            }
+        };
+        match self.coils.offset(address, 1) {
+            Some(offset) => {
+                let mut coil = [coil];
+                if let Some(refused) = self.coil_hooks.run(code, address, &mut coil) {
+                    return refused;
+                }
+                let old = changes::coil_values(&self.coils.values[offset..offset + 1]);
+                self.coils.values[offset] = coil[0];
+                self.changes.notify(Table::Coils, address, old, changes::coil_values(&coil));
                 ModbusResponsePDU::WriteSingleCoilResponse {
                     code: code , address:address, value: value
                 }
-            },                    
-            _ => ModbusResponsePDU::ModbusErrorResponse{
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
                 code:0x85,
-                exception_code:modbus::ExceptionCode::IllegalDataValue as u8
+                exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
             }
         }
     }
     
-    fn write_single_register ( &mut self,code:Code, address:Address, value:Quantity) ->ModbusResponsePDU {
-        self.holding_registers[address as usize] = value;
-        ModbusResponsePDU::WriteSingleCoilResponse {
-            code: code , address:address, value: value
+    fn write_single_register ( &mut self,code:Code, address:Address, value:Value) ->ModbusResponsePDU {
+        match self.holding_registers.offset(address, 1) {
+            Some(offset) => {
+                let mut written = [value];
+                if let Some(refused) = self.register_hooks.run(code, address, &mut written) {
+                    return refused;
+                }
+                let old = self.holding_registers.values[offset];
+                self.holding_registers.values[offset] = written[0];
+                self.changes.notify(Table::HoldingRegisters, address, vec![old], written.to_vec());
+                ModbusResponsePDU::WriteSingleRegisterResponse {
+                    code: code , address:address, value: value
+                }
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
+                code:0x86,
+                exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
+            }
         }
     }
     
-    fn read_discrete_inputs (&self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
-        if quantity > 2000 {
-            ModbusResponsePDU::ModbusErrorResponse{
+    fn read_discrete_inputs (&mut self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
+        if quantity < 1 || quantity > 2000 {
+            return ModbusResponsePDU::ModbusErrorResponse{
                 code:0x82,
                 exception_code:modbus::ExceptionCode::IllegalDataValue as u8
-            }
-        } else if address as usize + quantity as usize > self.discrete_registers.len() {
-            ModbusResponsePDU::ModbusErrorResponse{
+            };
+        }
+        match self.discrete_registers.offset(address, quantity) {
+            Some(offset) => {
+                let values :Vec<u8> = binary::pack_bits(
+                    &self.discrete_registers.values[offset .. offset + quantity as usize]);
+                ModbusResponsePDU::ReadDiscreteInputsResponse{
+                    code:code,byte_count: values.len() as u8,input_status:values}
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
                 code:0x82,
                 exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
             }
-            
-        } else {
-            let values :Vec<u8> = binary::pack_bits(
-                &self.discrete_registers[address as usize .. (address +quantity) as usize]);
-            ModbusResponsePDU::ReadDiscreteInputsResponse{
-                code:code,byte_count: values.len() as u8,input_status:values}
         }
     }
     
-    fn read_holding_registers (&self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
-        if quantity > 125 {
-            ModbusResponsePDU::ModbusErrorResponse{
+    fn read_holding_registers (&mut self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
+        if quantity < 1 || quantity > 125 {
+            return ModbusResponsePDU::ModbusErrorResponse{
                 code:0x83,
                 exception_code:modbus::ExceptionCode::IllegalDataValue as u8
-            }
-        } else if address as usize + quantity as usize > self.holding_registers.len() {
-            ModbusResponsePDU::ModbusErrorResponse{
+            };
+        }
+        match self.holding_registers.offset(address, quantity) {
+            Some(offset) => {
+                let values = self.holding_registers.values[offset..offset + quantity as usize].to_vec();
+                ModbusResponsePDU::ReadHoldingRegistersResponse{
+                    code:code,byte_count: (2 * quantity) as u8,values:values}
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
                 code:0x83,
                 exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
-            }            
-        } else {
-            let mut values :Vec<u16> = vec![0;quantity as usize];
-            println!("quantity {}",quantity);
-            values.copy_from_slice(&self.holding_registers[address as usize..address as usize + quantity as usize]);
-            ModbusResponsePDU::ReadHoldingRegistersResponse{
-                code:code,byte_count: quantity as u8,values:values}        
+            }
         }
     }
     
-    fn read_input_registers (&self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
-        if quantity > 125 {
-            ModbusResponsePDU::ModbusErrorResponse{
+    fn read_input_registers (&mut self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
+        if quantity < 1 || quantity > 125 {
+            return ModbusResponsePDU::ModbusErrorResponse{
                 code:0x84,
                 exception_code:modbus::ExceptionCode::IllegalDataValue as u8
-            }
-        } else if address as usize + quantity as usize > self.input_registers.len() {
-            ModbusResponsePDU::ModbusErrorResponse{
+            };
+        }
+        match self.input_registers.offset(address, quantity) {
+            Some(offset) => {
+                let values = self.input_registers.values[offset..offset + quantity as usize].to_vec();
+                ModbusResponsePDU::ReadInputRegistersResponse{
+                    code:code,byte_count: (2 * quantity) as u8,values:values}
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
                 code:0x84,
                 exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
-            }            
-        } else {
-            let mut values :Vec<u16> = vec![0;quantity as usize];
-            
-            values.copy_from_slice(&self.input_registers[address as usize..address as usize + quantity as usize]);
-            ModbusResponsePDU::ReadInputRegistersResponse{
-                code:code,byte_count: quantity as u8,values:values}        
+            }
         }
     }
     
-    fn read_coils (&self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
-        if quantity > 2000 {
-            ModbusResponsePDU::ModbusErrorResponse{
-                code:code +0x80,
+    fn read_coils (&mut self,code:Code, address:Address, quantity:Quantity) ->ModbusResponsePDU {
+        if quantity < 1 || quantity > 2000 {
+            return ModbusResponsePDU::ModbusErrorResponse{
+                code:0x81,
                 exception_code:modbus::ExceptionCode::IllegalDataValue as u8
-            }
-        } else if address as usize + quantity as usize > self.coils.len() {
-            ModbusResponsePDU::ModbusErrorResponse{
-                code:code +0x80,
+            };
+        }
+        match self.coils.offset(address, quantity) {
+            Some(offset) => {
+                let values :Vec<u8> = binary::pack_bits(
+                    &self.coils.values[offset .. offset + quantity as usize]);
+                ModbusResponsePDU::ReadCoilsResponse{
+                    code:code,byte_count: values.len() as u8,coil_status:values}
+            },
+            None => ModbusResponsePDU::ModbusErrorResponse{
+                code:0x81,
                 exception_code:modbus::ExceptionCode::IllegalDataAddress as u8
-            }            
-        } else {
-            let values :Vec<u8> = binary::pack_bits(
-                &self.coils[address as usize .. address as usize + quantity as usize]);
-            ModbusResponsePDU::ReadCoilsResponse{
-                code:code,byte_count: values.len() as u8,coil_status:values}
-        }        
+            }
+        }
     }
-    
-    pub fn call(& mut self, req: ModbusRequestPDU) -> ModbusResponsePDU {
-        println!("BR call");
-        let resp = match FunctionCode::from_u8(req.code).unwrap(){
-            FunctionCode::WriteMultipleCoils  => {
-                self.write_multiple_coils(
-                    req.code,
-                    req.address,
-                    req.q_or_v,
-                    binary::unpack_bits(
-                        &(req.addl.unwrap().data),
-                        req.q_or_v)
-                )
-            },
-            FunctionCode::WriteMultipleRegisters  => {
-                println!("WMR {:?}",req);
-                self.write_multiple_registers(
-                    req.code,
-                    req.address,
-                    req.q_or_v,
-                    binary::pack_bytes(
-                        &(req.addl.unwrap().data))
-                        .unwrap()
-                )
-            },
-            FunctionCode::WriteSingleCoil  => {
-                self.write_single_coil(
-                    req.code,
-                    req.address,
-                    req.q_or_v)
-            },
-             FunctionCode::WriteSingleRegister  => {
-                self.write_single_register(
-                    req.code,
-                    req.address,
-                    req.q_or_v)
-            },
-             FunctionCode::ReadHoldingRegisters  => {
-                self.read_holding_registers(
-                    req.code,
-                    req.address,
-                    req.q_or_v)
-            },
-             FunctionCode::ReadInputRegisters  => {
-                self.read_input_registers(
-                    req.code,
-                    req.address,
-                    req.q_or_v)
-            },
-             FunctionCode::ReadCoils  => {
-                self.read_coils(
-                    req.code,
-                    req.address,
-                    req.q_or_v)
-             },
-             FunctionCode::ReadDiscreteInputs  => {
-                self.read_discrete_inputs(
-                    req.code,
-                    req.address,
-                    req.q_or_v)
-             }
-        };
+
+    fn read_exception_status (&mut self,code:Code) ->ModbusResponsePDU {
+        ModbusResponsePDU::ReadExceptionStatusResponse{
+            code:code, status:self.exception_status}
+    }
+
+    fn diagnostic (&mut self,code:Code, sub_function:u16, data:Vec<u8>) ->ModbusResponsePDU {
+        let resp = self.diagnostics.respond(code, sub_function, &data);
+        if let ModbusResponsePDU::DiagnosticsResponse{..} = resp {
+            match sub_function {
+                diagnostics::RESTART_COMMUNICATIONS_OPTION => {
+                    if data == [0xFF, 0x00] {
+                        self.events.clear_events();
+                    }
+                    self.events.clear_counter();
+                    self.events.push(events::COMMUNICATION_RESTART);
+                },
+                diagnostics::CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER => {
+                    self.events.clear_counter();
+                },
+                _ => {}
+            }
+        }
         resp
     }
+
+    fn get_comm_event_counter (&mut self,code:Code) ->ModbusResponsePDU {
+        ModbusResponsePDU::GetCommEventCounterResponse{
+            code:code, status:0, event_count:self.events.event_count()}
+    }
+
+    fn get_comm_event_log (&mut self,code:Code) ->ModbusResponsePDU {
+        ModbusResponsePDU::GetCommEventLogResponse{
+            code:code,
+            status:0,
+            event_count:self.events.event_count(),
+            message_count:self.diagnostics.bus_message_count(),
+            events:self.events.events()
+        }
+    }
+
+    fn report_server_id (&mut self,code:Code) ->ModbusResponsePDU {
+        ModbusResponsePDU::ReportServerIdResponse{
+            code:code,
+            server_id:self.server_id.clone(),
+            run_indicator:self.run_indicator
+        }
+    }
+
+    fn read_file_record (&mut self,code:Code, data:&[u8]) ->ModbusResponsePDU {
+        self.files.read(code, data)
+    }
+
+    fn write_file_record (&mut self,code:Code, data:&[u8]) ->ModbusResponsePDU {
+        self.files.write(code, data)
+    }
+
+    fn read_device_identification (&mut self,code:Code, read_code:u8, object_id:u8) ->ModbusResponsePDU {
+        self.identification.read(code, read_code, object_id)
+    }
+}
+
+// A request whose data doesn't match its quantity.
+fn illegal_data_value(code: Code) -> ModbusResponsePDU {
+    ModbusResponsePDU::ModbusErrorResponse{
+        code:code | 0x80,
+        exception_code:modbus::ExceptionCode::IllegalDataValue as u8
+    }
+}
+
+fn illegal_function(code: Code) -> ModbusResponsePDU {
+    ModbusResponsePDU::ModbusErrorResponse{
+        code:code | 0x80,
+        exception_code:modbus::ExceptionCode::IllegalFunction as u8
+    }
 }
@@ -0,0 +1,87 @@
+// Notification of writes by masters, for applications that need to react
+// to them.
+//
+// Every write to coils or holding registers that goes through sends a
+// Change to each subscriber, whether or not the values actually differ.
+// Coils are given as 1 for on and 0 for off.
+
+use std::net::SocketAddr;
+
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use modbus;
+
+use {Address, Table, Value, Values};
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Change {
+    // The unit id the request was sent to, if the service passed it on
+    // with `call_from`; otherwise the one the block was told, if any.
+    pub unit_id: Option<u8>,
+    // The master's address, if the service knows it.
+    pub peer: Option<SocketAddr>,
+    pub table: Table,
+    pub address: Address,
+    pub old_values: Values,
+    pub new_values: Values
+}
+
+#[derive(Debug,Default)]
+pub struct ChangeNotifier {
+    unit_id: Option<u8>,
+    // Where the request being served came from.
+    source: Option<(Option<SocketAddr>, u8)>,
+    subscribers: Vec<UnboundedSender<Change>>
+}
+
+pub fn coil_values(coils: &[modbus::Coil]) -> Values {
+    coils.iter().map(|&coil| if coil == modbus::Coil::On { 1 } else { 0 }).collect()
+}
+
+impl ChangeNotifier {
+
+    pub fn new() -> ChangeNotifier {
+        ChangeNotifier::default()
+    }
+
+    pub fn set_unit_id(&mut self, unit_id: u8) {
+        self.unit_id = Some(unit_id);
+    }
+
+    // For the changes made by the request about to be served; cleared with
+    // `clear_source` once it has been.
+    pub fn set_source(&mut self, peer: Option<SocketAddr>, unit_id: u8) {
+        self.source = Some((peer, unit_id));
+    }
+
+    pub fn clear_source(&mut self) {
+        self.source = None;
+    }
+
+    // The stream ends when the block is dropped. Dropping the receiver
+    // unsubscribes.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<Change> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    pub fn is_watched(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn notify(&mut self, table: Table, address: Address, old_values: Values, new_values: Vec<Value>) {
+        let (unit_id, peer) = match self.source {
+            Some((peer, unit_id)) => (Some(unit_id), peer),
+            None => (self.unit_id, None)
+        };
+        let change = Change {
+            unit_id: unit_id,
+            peer: peer,
+            table: table,
+            address: address,
+            old_values: old_values,
+            new_values: new_values
+        };
+        self.subscribers.retain(|tx| tx.unbounded_send(change.clone()).is_ok());
+    }
+}
@@ -0,0 +1,434 @@
+// Register maps read from a JSON file, so that a test bench can be kept
+// under version control rather than built in code. For example:
+//
+// {
+//     "units": [
+//         {
+//             "unit_id": 1,
+//             "holding_registers": { "start": 0, "size": 200,
+//                                    "values": [100, 200, 300] },
+//             "input_registers": { "start": 1000, "size": 10,
+//                                  "values": { "1005": 42 } },
+//             "coils": { "start": 0, "size": 16, "access": "read-only",
+//                        "values": [true, false, true] }
+//         }
+//     ]
+// }
+//
+// A table that is left out covers the whole address space. Initial values
+// are either a list, starting at the table's first address, or an object
+// from address to value. Coils and discrete inputs take true or false.
+//
+// Access is one of "read-write" (the default), "read-only", "write-only" or
+// "no-access". Discrete inputs and input registers can only be
+// "read-only" or "no-access", since masters can never write them. Besides
+// access for a whole table, a unit can list rules for parts of tables,
+// each refused access failing with the given exception code or, by
+// default, IllegalDataAddress (2):
+//
+//             "access_rules": [
+//                 { "table": "holding_registers", "start": 100, "size": 10,
+//                   "access": "read-only", "exception": 1 }
+//             ]
+//
+// A unit can also have addresses whose values change over time (see
+// simulation.rs), each with a waveform and what it takes:
+//
+//             "simulations": [
+//                 { "table": "input_registers", "address": 1000,
+//                   "waveform": "sine", "min": 0, "max": 1000, "period": 60 },
+//                 { "table": "input_registers", "address": 1001,
+//                   "waveform": "steps", "values": [1, 2, 3], "interval": 5 }
+//             ]
+//
+// "sine", "ramp" and "square" take a period; "counter" a step and an
+// interval; "random_walk" a step; "steps" a list of values and an
+// interval. Min and max default to the whole range of a register, steps to
+// 1 and intervals to 1 second.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use modbus;
+use rustc_serialize::json::{self, Json};
+
+use {Address, Table, Value};
+use block::{BlankRegisters, BlankRegistersBuilder};
+use access::{Access, AccessRules};
+use simulation::{Simulation, Waveform};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Json(json::ParserError),
+    // Valid JSON that doesn't describe a register map.
+    Invalid(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "can't read configuration: {}", e),
+            ConfigError::Json(ref e) => write!(f, "configuration isn't valid JSON: {}", e),
+            ConfigError::Invalid(ref reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(_) => "can't read configuration",
+            ConfigError::Json(_) => "configuration isn't valid JSON",
+            ConfigError::Invalid(_) => "invalid configuration",
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<json::ParserError> for ConfigError {
+    fn from(e: json::ParserError) -> ConfigError {
+        ConfigError::Json(e)
+    }
+}
+
+fn invalid<T>(reason: String) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(reason))
+}
+
+#[derive(Debug,Clone)]
+pub struct TableConfig {
+    pub start: Address,
+    pub size: usize,
+    pub access: Access,
+    // Coils and discrete inputs are 1 for on and 0 for off.
+    pub values: BTreeMap<Address, Value>
+}
+
+impl Default for TableConfig {
+    fn default() -> TableConfig {
+        TableConfig { start: 0, size: 0x10000, access: Access::ReadWrite, values: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct UnitConfig {
+    pub unit_id: u8,
+    pub coils: TableConfig,
+    pub discrete_inputs: TableConfig,
+    pub holding_registers: TableConfig,
+    pub input_registers: TableConfig,
+    // From "access_rules"; the access for whole tables is kept with them.
+    pub access_rules: AccessRules,
+    // For the caller to run on the built block.
+    pub simulation: Simulation
+}
+
+#[derive(Debug,Clone)]
+pub struct Config {
+    pub units: Vec<UnitConfig>
+}
+
+fn to_coil(value: Value) -> modbus::Coil {
+    if value == 0 { modbus::Coil::Off } else { modbus::Coil::On }
+}
+
+impl UnitConfig {
+
+    pub fn build(&self) -> BlankRegisters {
+        let mut block = BlankRegistersBuilder::new()
+            .coils(self.coils.start, self.coils.size)
+            .discrete_inputs(self.discrete_inputs.start, self.discrete_inputs.size)
+            .holding_registers(self.holding_registers.start, self.holding_registers.size)
+            .input_registers(self.input_registers.start, self.input_registers.size)
+            .build();
+        let mut rules = self.access_rules.clone();
+        for &(table, ref config) in &[(Table::Coils, &self.coils),
+                                     (Table::DiscreteInputs, &self.discrete_inputs),
+                                     (Table::HoldingRegisters, &self.holding_registers),
+                                     (Table::InputRegisters, &self.input_registers)] {
+            if config.access != Access::ReadWrite {
+                rules.add(table, config.start, config.size, config.access);
+            }
+        }
+        block.set_access_rules(rules);
+        block.set_unit_id(self.unit_id);
+        // Parsing made sure every value is inside its table.
+        for (&address, &value) in &self.coils.values {
+            block.set_coils(address, &[to_coil(value)]);
+        }
+        for (&address, &value) in &self.discrete_inputs.values {
+            block.set_discrete_inputs(address, &[to_coil(value)]);
+        }
+        for (&address, &value) in &self.holding_registers.values {
+            block.set_holding_registers(address, &[value]);
+        }
+        for (&address, &value) in &self.input_registers.values {
+            block.set_input_registers(address, &[value]);
+        }
+        block
+    }
+}
+
+fn parse_u64(json: &Json, what: &str, max: u64) -> Result<u64, ConfigError> {
+    match json.as_u64() {
+        Some(n) if n <= max => Ok(n),
+        _ => invalid(format!("{} must be a number from 0 to {}", what, max))
+    }
+}
+
+fn parse_value(json: &Json, what: &str, bits: bool) -> Result<Value, ConfigError> {
+    if bits {
+        match json.as_boolean() {
+            Some(on) => Ok(on as Value),
+            None => invalid(format!("{} must be true or false", what))
+        }
+    } else {
+        parse_u64(json, what, 0xFFFF).map(|n| n as Value)
+    }
+}
+
+fn parse_access(json: &Json, name: &str, writable: bool) -> Result<Access, ConfigError> {
+    match (json.as_string(), writable) {
+        (Some("read-only"), _) => Ok(Access::ReadOnly),
+        (Some("no-access"), _) => Ok(Access::NoAccess),
+        (Some("read-write"), true) => Ok(Access::ReadWrite),
+        (Some("write-only"), true) => Ok(Access::WriteOnly),
+        (_, true) => invalid(format!("{} access must be \"read-write\", \"read-only\", \"write-only\" or \"no-access\"", name)),
+        (_, false) => invalid(format!("{} access must be \"read-only\" or \"no-access\"", name))
+    }
+}
+
+fn parse_rule(json: &Json, unit_id: u8, rules: &mut AccessRules) -> Result<(), ConfigError> {
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return invalid(format!("unit {} access rules must be objects", unit_id))
+    };
+    let (table, writable) = match object.get("table").and_then(|table| table.as_string()) {
+        Some("coils") => (Table::Coils, true),
+        Some("discrete_inputs") => (Table::DiscreteInputs, false),
+        Some("holding_registers") => (Table::HoldingRegisters, true),
+        Some("input_registers") => (Table::InputRegisters, false),
+        _ => return invalid(format!("unit {} access rule has no valid table", unit_id))
+    };
+    let name = format!("unit {} access rule", unit_id);
+    let start = match object.get("start") {
+        Some(start) => parse_u64(start, &format!("{} start", name), 0xFFFF)?,
+        None => 0
+    };
+    let size = match object.get("size") {
+        Some(size) => parse_u64(size, &format!("{} size", name), 0x10000 - start)?,
+        None => 0x10000 - start
+    };
+    let access = match object.get("access") {
+        Some(access) => parse_access(access, &name, writable)?,
+        None => return invalid(format!("{} has no access", name))
+    };
+    let exception_code = match object.get("exception") {
+        Some(code) => parse_u64(code, &format!("{} exception", name), 0xFF)? as u8,
+        None => modbus::ExceptionCode::IllegalDataAddress as u8
+    };
+    rules.add_with_exception(table, start as Address, size as usize, access, exception_code);
+    Ok(())
+}
+
+fn parse_seconds(json: Option<&Json>, what: &str) -> Result<f64, ConfigError> {
+    match json.map(|json| json.as_f64()) {
+        None => Ok(1.0),
+        Some(Some(seconds)) if seconds > 0.0 => Ok(seconds),
+        Some(_) => invalid(format!("{} must be a number of seconds more than 0", what))
+    }
+}
+
+fn parse_signal(json: &Json, unit_id: u8) -> Result<(Table, Address, Waveform), ConfigError> {
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return invalid(format!("unit {} simulations must be objects", unit_id))
+    };
+    let table = match object.get("table").and_then(|table| table.as_string()) {
+        Some("coils") => Table::Coils,
+        Some("discrete_inputs") => Table::DiscreteInputs,
+        Some("holding_registers") => Table::HoldingRegisters,
+        Some("input_registers") => Table::InputRegisters,
+        _ => return invalid(format!("unit {} simulation has no valid table", unit_id))
+    };
+    let name = format!("unit {} simulation", unit_id);
+    let address = match object.get("address") {
+        Some(address) => parse_u64(address, &format!("{} address", name), 0xFFFF)? as Address,
+        None => return invalid(format!("{} has no address", name))
+    };
+    let value = |key: &str, default: Value| match object.get(key) {
+        Some(value) => parse_u64(value, &format!("{} {}", name, key), 0xFFFF).map(|n| n as Value),
+        None => Ok(default)
+    };
+    let (min, max, step) = (value("min", 0)?, value("max", 0xFFFF)?, value("step", 1)?);
+    if min > max {
+        return invalid(format!("{} min is more than its max", name));
+    }
+    let period = || match object.get("period") {
+        Some(period) => parse_seconds(Some(period), &format!("{} period", name)),
+        None => invalid(format!("{} has no period", name))
+    };
+    let interval = parse_seconds(object.get("interval"), &format!("{} interval", name))?;
+    let waveform = match object.get("waveform").and_then(|waveform| waveform.as_string()) {
+        Some("sine") => Waveform::Sine { min: min, max: max, period: period()? },
+        Some("ramp") => Waveform::Ramp { min: min, max: max, period: period()? },
+        Some("square") => Waveform::Square { min: min, max: max, period: period()? },
+        Some("counter") => Waveform::Counter { min: min, max: max, step: step, interval: interval },
+        Some("random_walk") => Waveform::RandomWalk { min: min, max: max, step: step },
+        Some("steps") => {
+            let values = match object.get("values").and_then(|values| values.as_array()) {
+                Some(values) if !values.is_empty() => values,
+                _ => return invalid(format!("{} steps need a list of values", name))
+            };
+            let bits = table == Table::Coils || table == Table::DiscreteInputs;
+            let values = values.iter()
+                .map(|value| parse_value(value, &format!("{} value", name), bits))
+                .collect::<Result<Vec<Value>, ConfigError>>()?;
+            Waveform::Steps { values: values, interval: interval }
+        },
+        _ => return invalid(format!("{} waveform must be \"sine\", \"ramp\", \"square\", \"counter\", \"random_walk\" or \"steps\"", name))
+    };
+    Ok((table, address, waveform))
+}
+
+fn parse_table(json: &Json, name: &str, bits: bool, writable: bool) -> Result<TableConfig, ConfigError> {
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return invalid(format!("{} must be an object", name))
+    };
+    let mut table = TableConfig::default();
+    if let Some(start) = object.get("start") {
+        table.start = parse_u64(start, &format!("{} start", name), 0xFFFF)? as Address;
+    }
+    table.size = 0x10000 - table.start as usize;
+    if let Some(size) = object.get("size") {
+        table.size = parse_u64(size, &format!("{} size", name), table.size as u64)? as usize;
+    }
+    if let Some(access) = object.get("access") {
+        table.access = parse_access(access, name, writable)?;
+    }
+    let end = table.start as usize + table.size;
+    match object.get("values") {
+        None => {},
+        Some(&Json::Array(ref values)) => {
+            if values.len() > table.size {
+                return invalid(format!("{} has more values than addresses", name));
+            }
+            for (i, value) in values.iter().enumerate() {
+                let address = table.start as usize + i;
+                let value = parse_value(value, &format!("{} {}", name, address), bits)?;
+                table.values.insert(address as Address, value);
+            }
+        },
+        Some(&Json::Object(ref values)) => {
+            for (key, value) in values {
+                let address = match key.parse::<usize>() {
+                    Ok(address) if address >= table.start as usize && address < end => address,
+                    _ => return invalid(format!("{} has no address {}", name, key))
+                };
+                let value = parse_value(value, &format!("{} {}", name, address), bits)?;
+                table.values.insert(address as Address, value);
+            }
+        },
+        Some(_) => return invalid(format!("{} values must be a list or an object", name))
+    }
+    Ok(table)
+}
+
+fn parse_unit(json: &Json) -> Result<UnitConfig, ConfigError> {
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return invalid("each unit must be an object".to_string())
+    };
+    let unit_id = match object.get("unit_id") {
+        Some(unit_id) => parse_u64(unit_id, "unit_id", 0xFF)? as u8,
+        None => return invalid("each unit must have a unit_id".to_string())
+    };
+    let table = |name: &str, bits: bool, writable: bool| {
+        match object.get(name) {
+            Some(json) => parse_table(json, &format!("unit {} {}", unit_id, name), bits, writable),
+            None => Ok(TableConfig::default())
+        }
+    };
+    let coils = table("coils", true, true)?;
+    let discrete_inputs = table("discrete_inputs", true, false)?;
+    let holding_registers = table("holding_registers", false, true)?;
+    let input_registers = table("input_registers", false, false)?;
+    let mut access_rules = AccessRules::new();
+    match object.get("access_rules") {
+        None => {},
+        Some(&Json::Array(ref rules)) => {
+            for rule in rules {
+                parse_rule(rule, unit_id, &mut access_rules)?;
+            }
+        },
+        Some(_) => return invalid(format!("unit {} access_rules must be a list", unit_id))
+    }
+    let mut simulation = Simulation::new();
+    match object.get("simulations") {
+        None => {},
+        Some(&Json::Array(ref signals)) => {
+            for signal in signals {
+                let (table, address, waveform) = parse_signal(signal, unit_id)?;
+                let config = match table {
+                    Table::Coils => &coils,
+                    Table::DiscreteInputs => &discrete_inputs,
+                    Table::HoldingRegisters => &holding_registers,
+                    Table::InputRegisters => &input_registers
+                };
+                if address < config.start || address as usize >= config.start as usize + config.size {
+                    return invalid(format!("unit {} simulation address {} isn't in its table", unit_id, address));
+                }
+                if let Err(e) = simulation.add(table, address, waveform) {
+                    return invalid(format!("unit {} simulation at {}: {}", unit_id, address, e));
+                }
+            }
+        },
+        Some(_) => return invalid(format!("unit {} simulations must be a list", unit_id))
+    }
+    Ok(UnitConfig {
+        unit_id: unit_id,
+        coils: coils,
+        discrete_inputs: discrete_inputs,
+        holding_registers: holding_registers,
+        input_registers: input_registers,
+        access_rules: access_rules,
+        simulation: simulation
+    })
+}
+
+impl Config {
+
+    pub fn from_json(text: &str) -> Result<Config, ConfigError> {
+        let json = Json::from_str(text)?;
+        let units = match json.find("units").and_then(|units| units.as_array()) {
+            Some(units) => units,
+            None => return invalid("a list of units is required".to_string())
+        };
+        let mut config = Config { units: Vec::new() };
+        for unit in units {
+            let unit = parse_unit(unit)?;
+            if config.units.iter().any(|u| u.unit_id == unit.unit_id) {
+                return invalid(format!("unit {} appears twice", unit.unit_id));
+            }
+            config.units.push(unit);
+        }
+        Ok(config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Config::from_json(&text)
+    }
+}
@@ -0,0 +1,173 @@
+// Diagnostics (function 0x08) and the counters behind it.
+//
+// Bus counters are kept by the codecs, which see every frame on the line or
+// connection; server counters by the dispatch in the block. Both share one
+// Diagnostics through an Arc, so the counters are atomics.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use modbus;
+use {Code, ModbusResponsePDU};
+
+pub const RETURN_QUERY_DATA: u16 = 0x00;
+pub const RESTART_COMMUNICATIONS_OPTION: u16 = 0x01;
+pub const RETURN_DIAGNOSTIC_REGISTER: u16 = 0x02;
+pub const CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER: u16 = 0x0A;
+pub const RETURN_BUS_MESSAGE_COUNT: u16 = 0x0B;
+pub const RETURN_BUS_COMMUNICATION_ERROR_COUNT: u16 = 0x0C;
+pub const RETURN_BUS_EXCEPTION_ERROR_COUNT: u16 = 0x0D;
+pub const RETURN_SERVER_MESSAGE_COUNT: u16 = 0x0E;
+pub const RETURN_SERVER_NO_RESPONSE_COUNT: u16 = 0x0F;
+pub const RETURN_SERVER_NAK_COUNT: u16 = 0x10;
+pub const RETURN_SERVER_BUSY_COUNT: u16 = 0x11;
+pub const RETURN_BUS_CHARACTER_OVERRUN_COUNT: u16 = 0x12;
+pub const CLEAR_OVERRUN_COUNTER_AND_FLAG: u16 = 0x14;
+
+#[derive(Debug,Default)]
+pub struct Diagnostics {
+    diagnostic_register: AtomicUsize,
+    bus_messages: AtomicUsize,
+    bus_communication_errors: AtomicUsize,
+    bus_exception_errors: AtomicUsize,
+    server_messages: AtomicUsize,
+    server_no_responses: AtomicUsize,
+    server_naks: AtomicUsize,
+    server_busy: AtomicUsize,
+    bus_character_overruns: AtomicUsize
+}
+
+// The counters are 16 bits on the wire and roll over.
+fn read(counter: &AtomicUsize) -> u16 {
+    (counter.load(Ordering::Relaxed) & 0xFFFF) as u16
+}
+
+fn bump(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Diagnostics {
+
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    // A frame that made it through framing and its checksum, whoever it
+    // was addressed to.
+    pub fn count_bus_message(&self) {
+        bump(&self.bus_messages);
+    }
+
+    // A frame with a bad CRC or LRC, or that couldn't be framed at all.
+    pub fn count_bus_communication_error(&self) {
+        bump(&self.bus_communication_errors);
+    }
+
+    pub fn count_bus_exception_error(&self) {
+        bump(&self.bus_exception_errors);
+    }
+
+    // A request handed to the block for processing.
+    pub fn count_server_message(&self) {
+        bump(&self.server_messages);
+    }
+
+    // A broadcast, or any other request that gets no response.
+    pub fn count_server_no_response(&self) {
+        bump(&self.server_no_responses);
+    }
+
+    pub fn bus_message_count(&self) -> u16 {
+        read(&self.bus_messages)
+    }
+
+    pub fn bus_communication_error_count(&self) -> u16 {
+        read(&self.bus_communication_errors)
+    }
+
+    pub fn bus_exception_error_count(&self) -> u16 {
+        read(&self.bus_exception_errors)
+    }
+
+    pub fn server_message_count(&self) -> u16 {
+        read(&self.server_messages)
+    }
+
+    pub fn server_no_response_count(&self) -> u16 {
+        read(&self.server_no_responses)
+    }
+
+    pub fn clear(&self) {
+        for counter in &[&self.diagnostic_register,
+                         &self.bus_messages,
+                         &self.bus_communication_errors,
+                         &self.bus_exception_errors,
+                         &self.server_messages,
+                         &self.server_no_responses,
+                         &self.server_naks,
+                         &self.server_busy,
+                         &self.bus_character_overruns] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn respond(&self, code: Code, sub_function: u16, data: &[u8]) -> ModbusResponsePDU {
+        let counter = match sub_function {
+            RETURN_QUERY_DATA => return ModbusResponsePDU::DiagnosticsResponse {
+                code: code, sub_function: sub_function, data: data.to_vec()
+            },
+            RETURN_DIAGNOSTIC_REGISTER => &self.diagnostic_register,
+            RETURN_BUS_MESSAGE_COUNT => &self.bus_messages,
+            RETURN_BUS_COMMUNICATION_ERROR_COUNT => &self.bus_communication_errors,
+            RETURN_BUS_EXCEPTION_ERROR_COUNT => &self.bus_exception_errors,
+            RETURN_SERVER_MESSAGE_COUNT => &self.server_messages,
+            RETURN_SERVER_NO_RESPONSE_COUNT => &self.server_no_responses,
+            RETURN_SERVER_NAK_COUNT => &self.server_naks,
+            RETURN_SERVER_BUSY_COUNT => &self.server_busy,
+            RETURN_BUS_CHARACTER_OVERRUN_COUNT => &self.bus_character_overruns,
+            RESTART_COMMUNICATIONS_OPTION => {
+                // 0xFF00 asks for the event log to be cleared as well; the
+                // counters are cleared either way.
+                if data != [0x00, 0x00] && data != [0xFF, 0x00] {
+                    return illegal_data_value(code);
+                }
+                self.clear();
+                return ModbusResponsePDU::DiagnosticsResponse {
+                    code: code, sub_function: sub_function, data: data.to_vec()
+                };
+            },
+            CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER | CLEAR_OVERRUN_COUNTER_AND_FLAG => {
+                if data != [0x00, 0x00] {
+                    return illegal_data_value(code);
+                }
+                if sub_function == CLEAR_OVERRUN_COUNTER_AND_FLAG {
+                    self.bus_character_overruns.store(0, Ordering::Relaxed);
+                } else {
+                    self.clear();
+                }
+                return ModbusResponsePDU::DiagnosticsResponse {
+                    code: code, sub_function: sub_function, data: data.to_vec()
+                };
+            },
+            _ => return ModbusResponsePDU::ModbusErrorResponse {
+                code: code | 0x80,
+                exception_code: modbus::ExceptionCode::IllegalFunction as u8
+            }
+        };
+        if data != [0x00, 0x00] {
+            return illegal_data_value(code);
+        }
+        let value = read(counter);
+        ModbusResponsePDU::DiagnosticsResponse {
+            code: code,
+            sub_function: sub_function,
+            data: vec![(value >> 8) as u8, (value & 0xff) as u8]
+        }
+    }
+}
+
+fn illegal_data_value(code: Code) -> ModbusResponsePDU {
+    ModbusResponsePDU::ModbusErrorResponse {
+        code: code | 0x80,
+        exception_code: modbus::ExceptionCode::IllegalDataValue as u8
+    }
+}
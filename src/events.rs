@@ -0,0 +1,79 @@
+// The communication event counter and log of a serial line device, read
+// with Get Comm Event Counter (function 0x0B) and Get Comm Event Log
+// (function 0x0C).
+
+use std::collections::VecDeque;
+
+// The log keeps the most recent 64 events.
+pub const MAX_EVENTS: usize = 64;
+
+pub const COMMUNICATION_RESTART: u8 = 0x00;
+
+// Receive events have bit 7 set, send events bit 6.
+const RECEIVE_EVENT: u8 = 0x80;
+const BROADCAST_RECEIVED: u8 = 0x40;
+const SEND_EVENT: u8 = 0x40;
+const READ_EXCEPTION_SENT: u8 = 0x01;
+const SERVER_ABORT_EXCEPTION_SENT: u8 = 0x02;
+const SERVER_BUSY_EXCEPTION_SENT: u8 = 0x04;
+const SERVER_PROGRAM_NAK_EXCEPTION_SENT: u8 = 0x08;
+
+pub fn receive_event(broadcast: bool) -> u8 {
+    if broadcast {
+        RECEIVE_EVENT | BROADCAST_RECEIVED
+    } else {
+        RECEIVE_EVENT
+    }
+}
+
+// The send event for a response, given its exception code if it was one.
+pub fn send_event(exception_code: Option<u8>) -> u8 {
+    SEND_EVENT | match exception_code {
+        None => 0,
+        Some(0x01...0x03) => READ_EXCEPTION_SENT,
+        Some(0x04) => SERVER_ABORT_EXCEPTION_SENT,
+        Some(0x05...0x06) => SERVER_BUSY_EXCEPTION_SENT,
+        Some(0x07) => SERVER_PROGRAM_NAK_EXCEPTION_SENT,
+        Some(_) => 0
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct CommEventLog {
+    // Newest first.
+    events: VecDeque<u8>,
+    event_count: u16
+}
+
+impl CommEventLog {
+
+    pub fn new() -> CommEventLog {
+        CommEventLog::default()
+    }
+
+    pub fn push(&mut self, event: u8) {
+        self.events.push_front(event);
+        self.events.truncate(MAX_EVENTS);
+    }
+
+    // A message completed successfully. The counter rolls over.
+    pub fn count_event(&mut self) {
+        self.event_count = self.event_count.wrapping_add(1);
+    }
+
+    pub fn event_count(&self) -> u16 {
+        self.event_count
+    }
+
+    pub fn events(&self) -> Vec<u8> {
+        self.events.iter().cloned().collect()
+    }
+
+    pub fn clear_counter(&mut self) {
+        self.event_count = 0;
+    }
+
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+}
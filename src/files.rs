@@ -0,0 +1,143 @@
+// File records, read with Read File Record (function 0x14) and written
+// with Write File Record (function 0x15).
+//
+// A file is a sequence of 16 bit records, addressed by file number and
+// record number. Each request carries a list of sub-requests, one per
+// group of records.
+
+use std::collections::BTreeMap;
+use byteorder::{BigEndian, ByteOrder};
+
+use modbus;
+use {Code, ModbusResponsePDU};
+
+pub const REFERENCE_TYPE: u8 = 0x06;
+// Record numbers run from 0 to 0x270F.
+pub const MAX_RECORDS: usize = 10000;
+
+// Reference type, file number, record number and record length.
+const SUB_REQUEST_LEN: usize = 7;
+const MAX_PDU_LEN: usize = 253;
+
+#[derive(Debug,Clone,Default)]
+pub struct FileRecords {
+    files: BTreeMap<u16, Vec<u16>>
+}
+
+// One group of records named by a sub-request.
+struct SubRequest {
+    file_number: u16,
+    record_number: usize,
+    record_length: usize
+}
+
+fn exception(code: Code, exception_code: modbus::ExceptionCode) -> ModbusResponsePDU {
+    ModbusResponsePDU::ModbusErrorResponse {
+        code: code | 0x80,
+        exception_code: exception_code as u8
+    }
+}
+
+fn parse_sub_request(from: &[u8]) -> Option<SubRequest> {
+    if from.len() < SUB_REQUEST_LEN || from[0] != REFERENCE_TYPE {
+        return None;
+    }
+    Some(SubRequest {
+        file_number: BigEndian::read_u16(&from[1..3]),
+        record_number: BigEndian::read_u16(&from[3..5]) as usize,
+        record_length: BigEndian::read_u16(&from[5..7]) as usize
+    })
+}
+
+impl FileRecords {
+
+    pub fn new() -> FileRecords {
+        FileRecords::default()
+    }
+
+    // File numbers run from 1 to 0xFFFF. Files are cut to 10000 records.
+    pub fn set_file(&mut self, file_number: u16, mut records: Vec<u16>) {
+        records.truncate(MAX_RECORDS);
+        self.files.insert(file_number, records);
+    }
+
+    pub fn file(&self, file_number: u16) -> Option<&[u16]> {
+        self.files.get(&file_number).map(|records| records.as_slice())
+    }
+
+    pub fn remove_file(&mut self, file_number: u16) {
+        self.files.remove(&file_number);
+    }
+
+    fn check_address(&self, sub: &SubRequest) -> bool {
+        match self.files.get(&sub.file_number) {
+            Some(records) => {
+                sub.file_number != 0 &&
+                    sub.record_number < MAX_RECORDS &&
+                    sub.record_number + sub.record_length <= records.len()
+            },
+            None => false
+        }
+    }
+
+    pub fn read(&self, code: Code, data: &[u8]) -> ModbusResponsePDU {
+        if data.len() < SUB_REQUEST_LEN || data.len() > 0xF5 || data.len() % SUB_REQUEST_LEN != 0 {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        // Function code and response data length.
+        let mut length = 2;
+        let mut subs = Vec::new();
+        for chunk in data.chunks(SUB_REQUEST_LEN) {
+            let sub = match parse_sub_request(chunk) {
+                Some(sub) => sub,
+                None => return exception(code, modbus::ExceptionCode::IllegalDataValue)
+            };
+            // File response length, reference type and the records.
+            length += 2 + 2 * sub.record_length;
+            subs.push(sub);
+        }
+        if length > MAX_PDU_LEN {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        if !subs.iter().all(|sub| self.check_address(sub)) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        let records = subs.iter().map(|sub| {
+            self.files[&sub.file_number][sub.record_number..sub.record_number + sub.record_length].to_vec()
+        }).collect();
+        ModbusResponsePDU::ReadFileRecordResponse { code: code, records: records }
+    }
+
+    // Either every sub-request is written or, on any error, none is.
+    pub fn write(&mut self, code: Code, data: &[u8]) -> ModbusResponsePDU {
+        if data.len() < SUB_REQUEST_LEN + 2 || data.len() > 0xFB {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        let mut writes = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let sub = match parse_sub_request(rest) {
+                Some(sub) => sub,
+                None => return exception(code, modbus::ExceptionCode::IllegalDataValue)
+            };
+            let end = SUB_REQUEST_LEN + 2 * sub.record_length;
+            if sub.record_length == 0 || rest.len() < end {
+                return exception(code, modbus::ExceptionCode::IllegalDataValue);
+            }
+            let values: Vec<u16> = rest[SUB_REQUEST_LEN..end].chunks(2)
+                .map(|pair| BigEndian::read_u16(pair))
+                .collect();
+            writes.push((sub, values));
+            rest = &rest[end..];
+        }
+        if !writes.iter().all(|&(ref sub, _)| self.check_address(sub)) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        for (sub, values) in writes {
+            let records = self.files.get_mut(&sub.file_number).unwrap();
+            records[sub.record_number..sub.record_number + sub.record_length]
+                .copy_from_slice(&values);
+        }
+        ModbusResponsePDU::WriteFileRecordResponse { code: code, data: data.to_vec() }
+    }
+}
@@ -0,0 +1,89 @@
+// A handle for the application to read and update a block while it is
+// being served, from any thread. It shares the block with the services
+// through the same Arc<Mutex<..>>, so every call takes the lock for as
+// long as it runs and no longer.
+
+use std::sync::{Arc, Mutex};
+
+use modbus;
+use {Address, Quantity, Value, Values};
+use block::BlankRegisters;
+
+#[derive(Clone)]
+pub struct RegistersHandle {
+    block: Arc<Mutex<BlankRegisters>>
+}
+
+impl RegistersHandle {
+
+    pub fn new(block: BlankRegisters) -> RegistersHandle {
+        RegistersHandle::from_shared(Arc::new(Mutex::new(block)))
+    }
+
+    pub fn from_shared(block: Arc<Mutex<BlankRegisters>>) -> RegistersHandle {
+        RegistersHandle { block: block }
+    }
+
+    // For the service answering masters.
+    pub fn shared(&self) -> Arc<Mutex<BlankRegisters>> {
+        self.block.clone()
+    }
+
+    // Several reads and writes under one lock, so that masters never see
+    // them half done.
+    pub fn update<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut BlankRegisters) -> R
+    {
+        f(&mut self.block.lock().unwrap())
+    }
+
+    // As with the block's own setters, these return false and write
+    // nothing if the table doesn't hold every address.
+    pub fn set_coils(&self, address: Address, values: &[modbus::Coil]) -> bool {
+        self.update(|block| block.set_coils(address, values))
+    }
+
+    pub fn set_discrete_inputs(&self, address: Address, values: &[modbus::Coil]) -> bool {
+        self.update(|block| block.set_discrete_inputs(address, values))
+    }
+
+    pub fn set_holding_registers(&self, address: Address, values: &[Value]) -> bool {
+        self.update(|block| block.set_holding_registers(address, values))
+    }
+
+    pub fn set_input_registers(&self, address: Address, values: &[Value]) -> bool {
+        self.update(|block| block.set_input_registers(address, values))
+    }
+
+    pub fn set_coil(&self, address: Address, value: modbus::Coil) -> bool {
+        self.set_coils(address, &[value])
+    }
+
+    pub fn set_discrete_input(&self, address: Address, value: modbus::Coil) -> bool {
+        self.set_discrete_inputs(address, &[value])
+    }
+
+    pub fn set_holding_register(&self, address: Address, value: Value) -> bool {
+        self.set_holding_registers(address, &[value])
+    }
+
+    pub fn set_input_register(&self, address: Address, value: Value) -> bool {
+        self.set_input_registers(address, &[value])
+    }
+
+    pub fn coils(&self, address: Address, quantity: Quantity) -> Option<Vec<modbus::Coil>> {
+        self.update(|block| block.coils(address, quantity))
+    }
+
+    pub fn discrete_inputs(&self, address: Address, quantity: Quantity) -> Option<Vec<modbus::Coil>> {
+        self.update(|block| block.discrete_inputs(address, quantity))
+    }
+
+    pub fn holding_registers(&self, address: Address, quantity: Quantity) -> Option<Values> {
+        self.update(|block| block.holding_registers(address, quantity))
+    }
+
+    pub fn input_registers(&self, address: Address, quantity: Quantity) -> Option<Values> {
+        self.update(|block| block.input_registers(address, quantity))
+    }
+}
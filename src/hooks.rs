@@ -0,0 +1,71 @@
+// Callbacks run when a master writes coils or holding registers, after the
+// request has been checked but before anything is stored.
+//
+// A hook is registered for a range of addresses and sees every write that
+// touches that range, whole: the address of the first value written and
+// the values themselves. It can change the values, accept them as they
+// are, or refuse the write, which then fails with the exception code given
+// and changes nothing. Hooks run in the order they were added until one
+// refuses.
+
+use {Address, Code, ModbusResponsePDU};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum WriteResult {
+    Accept,
+    Reject(u8)
+}
+
+struct Hook<T> {
+    start: usize,
+    end: usize,
+    hook: Box<FnMut(Address, &mut [T]) -> WriteResult + Send>
+}
+
+pub struct Hooks<T> {
+    hooks: Vec<Hook<T>>
+}
+
+impl<T> Default for Hooks<T> {
+    fn default() -> Hooks<T> {
+        Hooks { hooks: Vec::new() }
+    }
+}
+
+impl<T> Hooks<T> {
+
+    pub fn new() -> Hooks<T> {
+        Hooks::default()
+    }
+
+    pub fn add<F>(&mut self, start: Address, size: usize, hook: F)
+        where F: FnMut(Address, &mut [T]) -> WriteResult + Send + 'static
+    {
+        self.hooks.push(Hook {
+            start: start as usize,
+            end: start as usize + size,
+            hook: Box::new(hook)
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.hooks.clear();
+    }
+
+    // The exception response if a hook refused the write.
+    pub fn run(&mut self, code: Code, address: Address, values: &mut [T]) -> Option<ModbusResponsePDU> {
+        let start = address as usize;
+        let end = start + values.len();
+        for hook in &mut self.hooks {
+            if hook.start < end && start < hook.end {
+                if let WriteResult::Reject(exception_code) = (hook.hook)(address, values) {
+                    return Some(ModbusResponsePDU::ModbusErrorResponse {
+                        code: code | 0x80,
+                        exception_code: exception_code
+                    });
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,138 @@
+// Read Device Identification (function 0x2B, MEI type 0x0E).
+//
+// Objects 0x00-0x02 make up the basic category and are mandatory, 0x03-0x7F
+// the regular one and 0x80-0xFF the extended, vendor specific one. A stream
+// read of a category also returns the categories below it.
+
+use std::collections::BTreeMap;
+
+use modbus;
+use {Code, ModbusResponsePDU};
+
+pub const MEI_READ_DEVICE_ID: u8 = 0x0E;
+
+pub const VENDOR_NAME: u8 = 0x00;
+pub const PRODUCT_CODE: u8 = 0x01;
+pub const MAJOR_MINOR_REVISION: u8 = 0x02;
+pub const VENDOR_URL: u8 = 0x03;
+pub const PRODUCT_NAME: u8 = 0x04;
+pub const MODEL_NAME: u8 = 0x05;
+pub const USER_APPLICATION_NAME: u8 = 0x06;
+
+// Read Device ID codes.
+const BASIC_STREAM: u8 = 0x01;
+const REGULAR_STREAM: u8 = 0x02;
+const EXTENDED_STREAM: u8 = 0x03;
+const INDIVIDUAL: u8 = 0x04;
+
+// Function code, MEI type, Read Device ID code, conformity level, more
+// follows, next object id and number of objects.
+const RESPONSE_HEADER_LEN: usize = 7;
+const MAX_PDU_LEN: usize = 253;
+// So that any single object fits in a response on its own, along with its
+// id and length.
+const MAX_OBJECT_LEN: usize = MAX_PDU_LEN - RESPONSE_HEADER_LEN - 2;
+
+#[derive(Debug,Clone)]
+pub struct DeviceIdentification {
+    objects: BTreeMap<u8, Vec<u8>>
+}
+
+impl Default for DeviceIdentification {
+    fn default() -> DeviceIdentification {
+        DeviceIdentification::new("", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    }
+}
+
+impl DeviceIdentification {
+
+    pub fn new(vendor_name: &str, product_code: &str, revision: &str) -> DeviceIdentification {
+        let mut identification = DeviceIdentification { objects: BTreeMap::new() };
+        identification.set_object(VENDOR_NAME, vendor_name);
+        identification.set_object(PRODUCT_CODE, product_code);
+        identification.set_object(MAJOR_MINOR_REVISION, revision);
+        identification
+    }
+
+    // Values longer than fits in a single response are truncated.
+    pub fn set_object<T: Into<Vec<u8>>>(&mut self, id: u8, value: T) {
+        let mut value = value.into();
+        value.truncate(MAX_OBJECT_LEN);
+        self.objects.insert(id, value);
+    }
+
+    // The basic objects can't be removed, only changed.
+    pub fn remove_object(&mut self, id: u8) {
+        if id > MAJOR_MINOR_REVISION {
+            self.objects.remove(&id);
+        }
+    }
+
+    // Highest category with any objects, with bit 7 set since individual
+    // access is always supported.
+    fn conformity_level(&self) -> u8 {
+        let level = match self.objects.keys().next_back() {
+            Some(&id) if id >= 0x80 => EXTENDED_STREAM,
+            Some(&id) if id > MAJOR_MINOR_REVISION => REGULAR_STREAM,
+            _ => BASIC_STREAM
+        };
+        0x80 | level
+    }
+
+    pub fn read(&self, code: Code, read_code: u8, object_id: u8) -> ModbusResponsePDU {
+        let last = match read_code {
+            BASIC_STREAM => MAJOR_MINOR_REVISION,
+            REGULAR_STREAM => 0x7F,
+            EXTENDED_STREAM => 0xFF,
+            INDIVIDUAL => {
+                return match self.objects.get(&object_id) {
+                    Some(value) => ModbusResponsePDU::ReadDeviceIdentificationResponse {
+                        code: code,
+                        read_code: read_code,
+                        conformity_level: self.conformity_level(),
+                        more_follows: false,
+                        next_object_id: 0,
+                        objects: vec![(object_id, value.clone())]
+                    },
+                    None => ModbusResponsePDU::ModbusErrorResponse {
+                        code: code | 0x80,
+                        exception_code: modbus::ExceptionCode::IllegalDataAddress as u8
+                    }
+                };
+            },
+            _ => return ModbusResponsePDU::ModbusErrorResponse {
+                code: code | 0x80,
+                exception_code: modbus::ExceptionCode::IllegalDataValue as u8
+            }
+        };
+        // An object id outside the category, or one that doesn't exist,
+        // restarts the stream at the beginning.
+        let first = if object_id <= last && self.objects.contains_key(&object_id) {
+            object_id
+        } else {
+            VENDOR_NAME
+        };
+        let mut objects = Vec::new();
+        let mut length = RESPONSE_HEADER_LEN;
+        let mut next_object_id = None;
+        for (&id, value) in self.objects.range(first..) {
+            if id > last {
+                break;
+            }
+            if length + 2 + value.len() > MAX_PDU_LEN {
+                next_object_id = Some(id);
+                break;
+            }
+            length += 2 + value.len();
+            objects.push((id, value.clone()));
+        }
+        ModbusResponsePDU::ReadDeviceIdentificationResponse {
+            code: code,
+            read_code: read_code,
+            conformity_level: self.conformity_level(),
+            more_follows: next_object_id.is_some(),
+            next_object_id: next_object_id.unwrap_or(0),
+            objects: objects
+        }
+    }
+}
@@ -5,15 +5,51 @@ extern crate byteorder;
 extern crate rustc_serialize;
 extern crate tokio_core;
 extern crate tokio_proto;
+extern crate tokio_service;
+extern crate futures;
 
 pub mod block ;
-pub use block::BlankRegisters;
+pub use block::{BlankRegisters, BlankRegistersBuilder, ModbusBlock};
+pub mod rtu;
+pub use rtu::{ModbusRTUCodec, ModbusRTUProto, ModbusRTURequest, ModbusRTUResponse};
+pub mod ascii;
+pub use ascii::{ModbusASCIICodec, ModbusASCIIRequest, ModbusASCIIResponse};
+pub mod udp;
+pub use udp::{ModbusUDPCodec, serve_udp};
+pub mod identification;
+pub use identification::DeviceIdentification;
+pub mod diagnostics;
+pub use diagnostics::Diagnostics;
+pub mod events;
+pub use events::CommEventLog;
+pub mod files;
+pub use files::FileRecords;
+pub mod sparse;
+pub use sparse::SparseRegisters;
+pub mod config;
+pub use config::{Config, ConfigError};
+pub mod values;
+pub use values::{RegisterOrder, RegisterValue};
+pub mod access;
+pub use access::{Access, AccessRules};
+pub mod hooks;
+pub use hooks::WriteResult;
+pub mod changes;
+pub use changes::Change;
+pub mod handle;
+pub use handle::RegistersHandle;
+pub mod snapshot;
+pub use snapshot::{SavePolicy, Snapshot, SnapshotError};
+pub mod simulation;
+pub use simulation::{Simulation, Waveform, WaveformError};
 
 use tokio_core::io::{Io, Codec, Framed, EasyBuf};
 
 use modbus::{Coil,binary,Reason,ExceptionCode,tcp};
-use byteorder::{BigEndian, ReadBytesExt,WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt,WriteBytesExt};
 use std::io::Cursor;
+use std::{error, fmt};
+use std::sync::Arc;
 use tokio_core::reactor::Core;
 use tokio_proto::pipeline::ServerProto;
 
@@ -29,14 +65,42 @@ pub enum FunctionCode {
     ReadInputRegisters = 0x04,
     WriteSingleCoil = 0x05,
     WriteSingleRegister = 0x06,
+    ReadExceptionStatus = 0x07,
+    Diagnostics = 0x08,
+    GetCommEventCounter = 0x0b,
+    GetCommEventLog = 0x0c,
     WriteMultipleCoils = 0x0f,
-    WriteMultipleRegisters = 0x10
+    WriteMultipleRegisters = 0x10,
+    ReportServerId = 0x11,
+    ReadFileRecord = 0x14,
+    WriteFileRecord = 0x15,
+    MaskWriteRegister = 0x16,
+    ReadWriteMultipleRegisters = 0x17,
+    ReadFIFOQueue = 0x18,
+    EncapsulatedInterfaceTransport = 0x2b
 }
 }
 
+// The four primary tables of the data model.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Table {
+    Coils,
+    DiscreteInputs,
+    HoldingRegisters,
+    InputRegisters
+}
+
 
 #[derive(Default)]
-pub struct ModbusTCPCodec;
+pub struct ModbusTCPCodec {
+    diagnostics: Arc<Diagnostics>
+}
+
+impl ModbusTCPCodec {
+    pub fn new(diagnostics: Arc<Diagnostics>) -> ModbusTCPCodec {
+        ModbusTCPCodec { diagnostics: diagnostics }
+    }
+}
 
 type Code = u8;
 type Count = u8;
@@ -63,6 +127,29 @@ pub enum ModbusResponsePDU {
     WriteSingleRegisterResponse { code:Code,address:Address,value:Value},
     WriteMultipleCoilsResponse { code:Code, address:Address, quantity:Quantity},
     WriteMultipleRegistersResponse { code:Code, address:Address, quantity:Quantity},
+    ReadExceptionStatusResponse { code:Code, status:u8},
+    DiagnosticsResponse { code:Code, sub_function:u16, data:Vec<u8>},
+    GetCommEventCounterResponse { code:Code, status:u16, event_count:u16},
+    GetCommEventLogResponse { code:Code,
+                              status:u16,
+                              event_count:u16,
+                              message_count:u16,
+                              events:Vec<u8>},
+    ReportServerIdResponse { code:Code, server_id:Vec<u8>, run_indicator:bool},
+    MaskWriteRegisterResponse { code:Code, address:Address, and_mask:Value, or_mask:Value},
+    ReadWriteMultipleRegistersResponse { code:Code,
+                                         byte_count: Count,
+                                         values:Values},
+    ReadFIFOQueueResponse { code:Code, values:Values},
+    ReadFileRecordResponse { code:Code, records:Vec<Values>},
+    // The write is echoed back, sub-requests and all.
+    WriteFileRecordResponse { code:Code, data:Vec<u8>},
+    ReadDeviceIdentificationResponse { code:Code,
+                                       read_code: u8,
+                                       conformity_level: u8,
+                                       more_follows: bool,
+                                       next_object_id: u8,
+                                       objects: Vec<(u8, Vec<u8>)>},
     ModbusErrorResponse {code:Code, exception_code:Code}
 }
 
@@ -81,6 +168,10 @@ impl ModbusResponsePDU {
             ModbusResponsePDU::ReadInputRegistersResponse{
                 code:c,byte_count:b,
                 values: ref v
+            } |
+            ModbusResponsePDU::ReadWriteMultipleRegistersResponse{
+                code:c,byte_count:b,
+                values: ref v
             } => {
                 buff.write_u8(c);
                 buff.write_u8(b);
@@ -129,6 +220,85 @@ impl ModbusResponsePDU {
                 buff.write_u8(c);
                 buff.write_u16::<BigEndian>(a);
                 buff.write_u16::<BigEndian>(q);
+            },
+            ModbusResponsePDU::ReadExceptionStatusResponse{code:c,status:s} => {
+                buff.write_u8(c);
+                buff.write_u8(s);
+            },
+            ModbusResponsePDU::GetCommEventCounterResponse{
+                code:c,status:s,event_count:e } => {
+                buff.write_u8(c);
+                buff.write_u16::<BigEndian>(s);
+                buff.write_u16::<BigEndian>(e);
+            },
+            ModbusResponsePDU::GetCommEventLogResponse{
+                code:c,status:s,event_count:e,message_count:m,events:ref events } => {
+                buff.write_u8(c);
+                buff.write_u8(6 + events.len() as u8);
+                buff.write_u16::<BigEndian>(s);
+                buff.write_u16::<BigEndian>(e);
+                buff.write_u16::<BigEndian>(m);
+                buff.write(events);
+            },
+            ModbusResponsePDU::ReportServerIdResponse{
+                code:c,server_id:ref id,run_indicator:r } => {
+                buff.write_u8(c);
+                buff.write_u8(id.len() as u8 + 1);
+                buff.write(id);
+                buff.write_u8(if r { 0xFF } else { 0x00 });
+            },
+            ModbusResponsePDU::DiagnosticsResponse{
+                code:c,sub_function:f,data:ref d } => {
+                buff.write_u8(c);
+                buff.write_u16::<BigEndian>(f);
+                buff.write(d);
+            },
+            ModbusResponsePDU::MaskWriteRegisterResponse{
+                code:c,address:a,and_mask:m,or_mask:o } => {
+                buff.write_u8(c);
+                buff.write_u16::<BigEndian>(a);
+                buff.write_u16::<BigEndian>(m);
+                buff.write_u16::<BigEndian>(o);
+            },
+            ModbusResponsePDU::ReadFIFOQueueResponse{code:c,values:ref v} => {
+                buff.write_u8(c);
+                // Unusually, the byte count is two bytes and includes the
+                // FIFO count.
+                buff.write_u16::<BigEndian>(2 + 2 * v.len() as u16);
+                buff.write_u16::<BigEndian>(v.len() as u16);
+                buff.write(binary::unpack_bytes(v).as_slice());
+            },
+            ModbusResponsePDU::ReadFileRecordResponse{code:c,records:ref records} => {
+                buff.write_u8(c);
+                let length = records.iter().map(|r| 2 + 2 * r.len()).sum::<usize>();
+                buff.write_u8(length as u8);
+                for r in records {
+                    // The file response length covers the reference type.
+                    buff.write_u8(1 + 2 * r.len() as u8);
+                    buff.write_u8(files::REFERENCE_TYPE);
+                    buff.write(binary::unpack_bytes(r).as_slice());
+                }
+            },
+            ModbusResponsePDU::WriteFileRecordResponse{code:c,data:ref d} => {
+                buff.write_u8(c);
+                buff.write_u8(d.len() as u8);
+                buff.write(d);
+            },
+            ModbusResponsePDU::ReadDeviceIdentificationResponse{
+                code:c,read_code:r,conformity_level:l,more_follows:m,
+                next_object_id:n,objects:ref objects } => {
+                buff.write_u8(c);
+                buff.write_u8(identification::MEI_READ_DEVICE_ID);
+                buff.write_u8(r);
+                buff.write_u8(l);
+                buff.write_u8(if m { 0xFF } else { 0x00 });
+                buff.write_u8(n);
+                buff.write_u8(objects.len() as u8);
+                for &(id, ref value) in objects {
+                    buff.write_u8(id);
+                    buff.write_u8(value.len() as u8);
+                    buff.write(value);
+                }
             }
         }
         buff
@@ -158,6 +328,14 @@ impl Header {
 }
 
 
+// The data following the address and quantity words of a request. For
+// Read/Write Multiple Registers the write address and write quantity come
+// first, and byte_count only covers the register values after them.
+// Functions without a byte count of their own, like the OR mask of Mask
+// Write Register or the data of a Diagnostics sub-function, get the length
+// of the data. Encapsulated Interface
+// Transport has no address or quantity, so its MEI type and data all go
+// in the footer.
 #[derive(Debug,Clone)]
 pub struct ModbusFooter {
     byte_count:u8,
@@ -173,6 +351,13 @@ pub struct ModbusRequestPDU {
     addl: Option<ModbusFooter>
 }
 
+impl ModbusRequestPDU {
+    // For answering with an exception without looking any further.
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+}
+
 #[derive(Debug)]
 pub struct ModbusTCPRequest {
     pub header: Header,
@@ -184,50 +369,276 @@ pub struct ModbusTCPResponse {
     pub pdu: ModbusResponsePDU
 }
 
-fn parse_mbap (from: &[u8]) -> Header {
-    let mut rdr = Cursor::new(from);
-    Header{
-        tid: rdr.read_u16::<BigEndian>().unwrap(),
-        pid: rdr.read_u16::<BigEndian>().unwrap(),
-        len: rdr.read_u16::<BigEndian>().unwrap(),
-        uid: rdr.read_u8().unwrap(),
+impl ModbusTCPResponse {
+    // The header comes from the request, so its length field has to be
+    // recomputed for the response PDU (plus the unit id).
+    fn encode (&self) -> Vec<u8> {
+        let pdu = self.pdu.encode();
+        let mut header = self.header.clone();
+        header.len = pdu.len() as u16 + 1;
+        let mut buff = header.encode();
+        buff.extend_from_slice(&pdu);
+        buff
+    }
+}
+
+// Why a frame could not be turned into a request. Unsupported function
+// codes are not decode errors: they reach the block, which answers them
+// with an IllegalFunction exception.
+#[derive(Debug,Clone,PartialEq)]
+pub enum DecodeError {
+    // The frame ended before all the fields its function code requires.
+    Truncated,
+    // An MBAP protocol id other than 0 (Modbus).
+    InvalidProtocolId(u16),
+    // An MBAP length that can't hold a PDU, would exceed the maximum ADU
+    // size, or disagrees with the size of the datagram.
+    InvalidLength(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "truncated Modbus frame"),
+            DecodeError::InvalidProtocolId(pid) => write!(f, "invalid MBAP protocol id {}", pid),
+            DecodeError::InvalidLength(len) => write!(f, "invalid MBAP length {}", len),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::Truncated => "truncated Modbus frame",
+            DecodeError::InvalidProtocolId(_) => "invalid MBAP protocol id",
+            DecodeError::InvalidLength(_) => "invalid MBAP length",
+        }
     }
 }
 
-fn parse_modbus_request_pdu(from: &[u8]) -> ModbusRequestPDU {
+// Reads from an in-memory frame can only fail by running out of bytes.
+impl From<io::Error> for DecodeError {
+    fn from(_: io::Error) -> DecodeError {
+        DecodeError::Truncated
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+fn parse_mbap (from: &[u8]) -> Result<Header, DecodeError> {
+    let mut rdr = Cursor::new(from);
+    Ok(Header{
+        tid: rdr.read_u16::<BigEndian>()?,
+        pid: rdr.read_u16::<BigEndian>()?,
+        len: rdr.read_u16::<BigEndian>()?,
+        uid: rdr.read_u8()?,
+    })
+}
+
+fn parse_modbus_request_pdu(from: &[u8]) -> Result<ModbusRequestPDU, DecodeError> {
     let mut rdr = Cursor::new(from);
 
-    let code = rdr.read_u8().unwrap();
-    let address = rdr.read_u16::<BigEndian>().unwrap();
-    let count =  rdr.read_u16::<BigEndian>().unwrap();
+    let code = rdr.read_u8()?;
+    let function = match FunctionCode::from_u8(code) {
+        Some(function) => function,
+        // The layout is unknown, so the code is all that can be kept.
+        None => return Ok(ModbusRequestPDU{
+            code:code,
+            address:0,
+            q_or_v:0,
+            addl:None
+        })
+    };
+    let (address, count) = match function {
+        FunctionCode::EncapsulatedInterfaceTransport |
+        FunctionCode::ReadFileRecord |
+        FunctionCode::WriteFileRecord |
+        FunctionCode::ReadExceptionStatus |
+        FunctionCode::GetCommEventCounter |
+        FunctionCode::GetCommEventLog |
+        FunctionCode::ReportServerId => (0, 0),
+        // The sub-function or FIFO pointer goes in the address.
+        FunctionCode::Diagnostics |
+        FunctionCode::ReadFIFOQueue => (rdr.read_u16::<BigEndian>()?, 0),
+        _ => (rdr.read_u16::<BigEndian>()?, rdr.read_u16::<BigEndian>()?)
+    };
     let mut addl = None;
 
-    match FunctionCode::from_u8(code).unwrap()  {
+    match function {
         FunctionCode::WriteMultipleCoils  |
-        FunctionCode::WriteMultipleRegisters  => {
-            let mut buffer = Vec::new();
-            let byte_count = rdr.read_u8().unwrap();
-            rdr.read_to_end(&mut buffer);
+        FunctionCode::WriteMultipleRegisters => {
+            addl = Some(parse_footer(&mut rdr, Vec::new())?);
+            println!("addl {:?}",addl);
+        },
+        FunctionCode::ReadFileRecord |
+        FunctionCode::WriteFileRecord => {
+            addl = Some(parse_footer(&mut rdr, Vec::new())?);
+        },
+        FunctionCode::ReadWriteMultipleRegisters => {
+            // write address and write quantity.
+            let mut prefix = vec![0; 4];
+            rdr.read_exact(&mut prefix)?;
+            addl = Some(parse_footer(&mut rdr, prefix)?);
+        },
+        FunctionCode::MaskWriteRegister => {
+            // q_or_v holds the AND mask, the footer the OR mask.
+            let mut or_mask = vec![0; 2];
+            rdr.read_exact(&mut or_mask)?;
             addl = Some(ModbusFooter{
-                byte_count:byte_count,
-                data: buffer
+                byte_count:2,
+                data: or_mask
+            });
+        },
+        FunctionCode::Diagnostics => {
+            let mut data = Vec::new();
+            rdr.read_to_end(&mut data)?;
+            addl = Some(ModbusFooter{
+                byte_count:data.len() as u8,
+                data: data
+            });
+        },
+        FunctionCode::EncapsulatedInterfaceTransport => {
+            let mut data = Vec::new();
+            rdr.read_to_end(&mut data)?;
+            // The MEI type at the very least.
+            if data.is_empty() {
+                return Err(DecodeError::Truncated);
+            }
+            addl = Some(ModbusFooter{
+                byte_count:data.len() as u8,
+                data: data
             });
-            println!("addl {:?}",addl);
         },
         _ =>  {
 
         }
         
     };
-    ModbusRequestPDU{
+    Ok(ModbusRequestPDU{
         code:code as u8,
         address:address,
         q_or_v: count,
         addl:addl
+    })
+}
+
+// Size of the MBAP header, which is followed by a PDU of at most 253 bytes.
+const MBAP_LEN: usize = 7;
+const MAX_ADU_LEN: usize = 260;
+
+// Total length of the ADU announced by a header, once it is known to be a
+// Modbus header at all. The length field counts the unit id and the PDU.
+fn mbap_frame_len(header: &Header) -> Result<usize, DecodeError> {
+    let (pid, len) = (header.pid, header.len);
+    if pid != 0 {
+        return Err(DecodeError::InvalidProtocolId(pid));
+    }
+    // unit id + function code at the very least.
+    if len < 2 || MBAP_LEN - 1 + len as usize > MAX_ADU_LEN {
+        return Err(DecodeError::InvalidLength(len));
     }
+    Ok(MBAP_LEN - 1 + len as usize)
 }
 
+// A byte count and that many bytes, appended to `data`.
+fn parse_footer(rdr: &mut Cursor<&[u8]>, mut data: Vec<u8>) -> Result<ModbusFooter, DecodeError> {
+    let byte_count = rdr.read_u8()?;
+    let mut buffer = Vec::new();
+    rdr.read_to_end(&mut buffer)?;
+    if buffer.len() < byte_count as usize {
+        return Err(DecodeError::Truncated);
+    }
+    data.extend_from_slice(&buffer[..byte_count as usize]);
+    Ok(ModbusFooter{
+        byte_count:byte_count,
+        data: data
+    })
+}
 
+// MBAP header followed by the request PDU, as carried over TCP and UDP.
+fn parse_tcp_request(from: &[u8]) -> Result<ModbusTCPRequest, DecodeError> {
+    if from.len() < MBAP_LEN {
+        return Err(DecodeError::Truncated);
+    }
+    let header = parse_mbap(&from[0..MBAP_LEN])?;
+    if mbap_frame_len(&header)? != from.len() {
+        return Err(DecodeError::InvalidLength(header.len));
+    }
+    Ok(ModbusTCPRequest {
+        header:header,
+        pdu:parse_modbus_request_pdu(&from[MBAP_LEN..])?
+    })
+}
+
+// Expected length of the request PDU at the start of `pdu`, worked out from
+// its function code, for transports without a length field. Ok(None) means
+// more bytes are needed to tell; Err(()) means the function code is unknown
+// or doesn't determine the length.
+fn request_pdu_len(pdu: &[u8]) -> Result<Option<usize>, ()> {
+    if pdu.len() < 1 {
+        return Ok(None);
+    }
+    let length = match FunctionCode::from_u8(pdu[0]) {
+        Some(FunctionCode::WriteMultipleCoils) |
+        Some(FunctionCode::WriteMultipleRegisters) => {
+            // address, quantity, then the byte count.
+            if pdu.len() < 6 {
+                return Ok(None);
+            }
+            6 + pdu[5] as usize
+        },
+        Some(FunctionCode::ReadExceptionStatus) |
+        Some(FunctionCode::GetCommEventCounter) |
+        Some(FunctionCode::GetCommEventLog) |
+        Some(FunctionCode::ReportServerId) => 1,
+        Some(FunctionCode::ReadFIFOQueue) => 3,
+        Some(FunctionCode::ReadFileRecord) |
+        Some(FunctionCode::WriteFileRecord) => {
+            // The byte count covers all the sub-requests.
+            if pdu.len() < 2 {
+                return Ok(None);
+            }
+            2 + pdu[1] as usize
+        },
+        Some(FunctionCode::MaskWriteRegister) => 7,
+        Some(FunctionCode::Diagnostics) => {
+            if pdu.len() < 3 {
+                return Ok(None);
+            }
+            // Return Query Data echoes any amount of data, so RTU frames
+            // for it end where the CRC checks out.
+            if BigEndian::read_u16(&pdu[1..3]) == diagnostics::RETURN_QUERY_DATA {
+                return Err(());
+            }
+            5
+        },
+        Some(FunctionCode::ReadWriteMultipleRegisters) => {
+            // read address and quantity, write address and quantity, then
+            // the byte count.
+            if pdu.len() < 10 {
+                return Ok(None);
+            }
+            10 + pdu[9] as usize
+        },
+        Some(FunctionCode::EncapsulatedInterfaceTransport) => {
+            if pdu.len() < 2 {
+                return Ok(None);
+            }
+            // Only Read Device Identification has a known layout.
+            if pdu[1] != identification::MEI_READ_DEVICE_ID {
+                return Err(());
+            }
+            4
+        },
+        Some(_) => 5,
+        None => return Err(())
+    };
+    Ok(Some(length))
+}
 
 impl Codec for ModbusTCPCodec {
     // 
@@ -238,71 +649,56 @@ impl Codec for ModbusTCPCodec {
     // message is available; returns `Ok(None)` if the buffer does not yet
     // hold a complete message.
 
-    // Read first 12 bytes.
-    // Decide if more are needed. 
+    // Frames are delimited by the MBAP length alone. A header that isn't
+    // Modbus, or announces more than the maximum ADU size, means the stream
+    // can't be trusted any more, so the error closes the connection.
     fn decode(&mut self, buf: &mut EasyBuf) -> std::io::Result<Option<Self::In>> {
-        if buf.len() < 12 {
-            Ok(None)
-        } else {
-            let mut length:usize = 0;
-            let mut code:u8=0;
-            let mut byte_count:usize = 0 ;
-            // Scope created just for z so it goes away before we run parse()
-            {
-                let z = buf.as_slice();
-                code = z[7] as u8;
-                length = match FunctionCode::from_u8(code).unwrap() {
-                    FunctionCode::WriteMultipleCoils |
-                    FunctionCode::WriteMultipleRegisters => {
-                        if buf.len() == 12 {
-                            0;
-                        }
-                        byte_count = z[12] as usize;
-                        if buf.len() >= byte_count + 13 {
-                            byte_count+13
-                        } else {
-                            0
-                        }
-                    },
-                    _ => 12
-                }
+        if buf.len() < MBAP_LEN {
+            return Ok(None);
+        }
+        let length = match parse_mbap(&buf.as_slice()[0..MBAP_LEN]).and_then(|h| mbap_frame_len(&h)) {
+            Ok(length) => length,
+            Err(e) => {
+                self.diagnostics.count_bus_communication_error();
+                return Err(e.into());
             }
-            let S = &buf.drain_to(length);
-            let s = S.as_slice();
-
-            match length {
-                0 => Ok(None),
-
-                _ => {
-                    Ok(Some(ModbusTCPRequest {
-                        header:parse_mbap(&s[0..7]),
-                        pdu:parse_modbus_request_pdu(&s[7..length])
-                    }))
-                }
+        };
+        if buf.len() < length {
+            return Ok(None);
+        }
+        let frame = buf.drain_to(length);
+        match parse_tcp_request(frame.as_slice()) {
+            Ok(req) => {
+                self.diagnostics.count_bus_message();
+                Ok(Some(req))
+            },
+            Err(e) => {
+                self.diagnostics.count_bus_communication_error();
+                Err(e.into())
             }
         }
     }
 
-    // Attempt to decode a message assuming that the given buffer contains
-    // *all* remaining input data.
-    fn decode_eof(&mut self, buf: &mut EasyBuf) -> io::Result<ModbusTCPRequest> {
-        let s = buf.as_slice();
-        Ok(ModbusTCPRequest {
-            header:parse_mbap(&s[0..7]),
-            pdu:parse_modbus_request_pdu(&s[7..buf.len()])
-        })
-    }
-
     fn encode(&mut self, item: ModbusTCPResponse, into: &mut Vec<u8>) -> io::Result<()> {
-        into.write(item.header.encode().as_slice());
-        into.write(item.pdu.encode().as_slice());
+        into.write(item.encode().as_slice())?;
         Ok(())
     }
 }
 
 
 
-pub struct ModbusTCPProto;
+#[derive(Default)]
+pub struct ModbusTCPProto {
+    diagnostics: Arc<Diagnostics>
+}
+
+impl ModbusTCPProto {
+    // Count the traffic on every connection in `diagnostics`, usually the
+    // block's own.
+    pub fn new(diagnostics: Arc<Diagnostics>) -> ModbusTCPProto {
+        ModbusTCPProto { diagnostics: diagnostics }
+    }
+}
 
 impl<T: Io + 'static> ServerProto<T> for ModbusTCPProto {
     type Request = ModbusTCPRequest;
@@ -311,7 +707,7 @@ impl<T: Io + 'static> ServerProto<T> for ModbusTCPProto {
     type BindTransport = ::std::result::Result<Self::Transport,io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(ModbusTCPCodec))
+        Ok(io.framed(ModbusTCPCodec::new(self.diagnostics.clone())))
     }
 }
 mod test;
@@ -0,0 +1,200 @@
+// Modbus RTU framing, as used on RS-485 lines:
+//
+//   slave address (1) | PDU (1..253) | CRC-16 (2, low byte first)
+//
+// There is no length field, so frame boundaries are worked out from the
+// function code of the request PDU.
+
+use tokio_core::io::{Io, Codec, Framed, EasyBuf};
+use tokio_proto::pipeline::ServerProto;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use {ModbusRequestPDU, ModbusResponsePDU, Diagnostics};
+use {parse_modbus_request_pdu, request_pdu_len};
+
+// Slave address + function code + CRC.
+const MIN_FRAME_LEN: usize = 4;
+// Slave address + the largest PDU + CRC.
+const MAX_FRAME_LEN: usize = 256;
+
+#[derive(Default)]
+pub struct ModbusRTUCodec {
+    diagnostics: Arc<Diagnostics>,
+    // Set while skipping bytes after a bad frame, so that a single
+    // corrupted frame counts as one communication error.
+    resyncing: bool
+}
+
+impl ModbusRTUCodec {
+    pub fn new(diagnostics: Arc<Diagnostics>) -> ModbusRTUCodec {
+        ModbusRTUCodec { diagnostics: diagnostics, resyncing: false }
+    }
+
+    fn skip(&mut self, buf: &mut EasyBuf, count: usize) {
+        if !self.resyncing {
+            self.diagnostics.count_bus_communication_error();
+            self.resyncing = true;
+        }
+        buf.drain_to(count);
+    }
+}
+
+#[derive(Debug)]
+pub struct ModbusRTURequest {
+    pub slave: u8,
+    pub pdu: ModbusRequestPDU
+}
+
+#[derive(Debug)]
+pub struct ModbusRTUResponse {
+    pub slave: u8,
+    pub pdu: ModbusResponsePDU
+}
+
+// CRC-16/MODBUS: polynomial 0xA001 (reflected 0x8005), initial value 0xFFFF.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+fn check_crc(frame: &[u8]) -> bool {
+    let n = frame.len();
+    let crc = crc16(&frame[..n - 2]);
+    frame[n - 2] == (crc & 0xff) as u8 && frame[n - 1] == (crc >> 8) as u8
+}
+
+// Whether `s` starts with a whole frame for a function whose length is
+// known, with a good CRC.
+fn starts_with_known_frame(s: &[u8]) -> bool {
+    if s.len() < MIN_FRAME_LEN {
+        return false;
+    }
+    match request_pdu_len(&s[1..]) {
+        Ok(Some(pdu_len)) if s.len() >= pdu_len + 3 => check_crc(&s[..pdu_len + 3]),
+        _ => false
+    }
+}
+
+// Length of the request frame at the start of `s`, including address and
+// CRC. Ok(None) means more bytes are needed to tell; Err(n) that `s`
+// doesn't start with a frame, and the next n bytes should be skipped.
+//
+// For unknown function codes the only clue is a CRC that checks out over
+// the bytes received so far. Frames often arrive in pieces, so at a frame
+// boundary it is worth waiting for the rest, up to a whole frame's worth
+// of bytes - but not once a frame that can be recognised has come in after
+// them, or the master's later requests would be answered late, and RTU
+// has no transaction id to tell the answers apart. While `resyncing`, when
+// `s` most likely starts in the middle of a frame, there is no waiting.
+fn request_frame_len(s: &[u8], resyncing: bool) -> Result<Option<usize>, usize> {
+    if s.len() < MIN_FRAME_LEN {
+        return Ok(None);
+    }
+    match request_pdu_len(&s[1..]) {
+        Ok(Some(pdu_len)) if s.len() >= pdu_len + 3 => Ok(Some(pdu_len + 3)),
+        Ok(_) => Ok(None),
+        Err(()) => {
+            if let Some(length) = (MIN_FRAME_LEN..s.len() + 1).find(|&n| check_crc(&s[..n])) {
+                return Ok(Some(length));
+            }
+            match (1..s.len()).find(|&start| starts_with_known_frame(&s[start..])) {
+                Some(start) => Err(start),
+                None if !resyncing && s.len() < MAX_FRAME_LEN => Ok(None),
+                None => Err(1)
+            }
+        }
+    }
+}
+
+impl Codec for ModbusRTUCodec {
+    type In = ModbusRTURequest;
+    type Out = ModbusRTUResponse;
+
+    // Frames with a bad CRC are line noise as far as a slave is concerned:
+    // skip a byte and try to resynchronise rather than answering or
+    // dropping the connection. Unknown function codes with a good CRC are
+    // passed on so the block can answer them with an IllegalFunction
+    // exception.
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        loop {
+            let length = match request_frame_len(buf.as_slice(), self.resyncing) {
+                Ok(Some(length)) => length,
+                Ok(None) => return Ok(None),
+                Err(count) => {
+                    self.skip(buf, count);
+                    continue;
+                }
+            };
+            if !check_crc(&buf.as_slice()[..length]) {
+                self.skip(buf, 1);
+                continue;
+            }
+            let frame = buf.drain_to(length);
+            let s = frame.as_slice();
+            // A frame that checks out ends any resynchronising, even if it
+            // can't be made sense of.
+            self.resyncing = false;
+            match parse_modbus_request_pdu(&s[1..length - 2]) {
+                Ok(pdu) => {
+                    self.diagnostics.count_bus_message();
+                    return Ok(Some(ModbusRTURequest {
+                        slave: s[0],
+                        pdu: pdu
+                    }));
+                },
+                Err(_) => self.diagnostics.count_bus_communication_error()
+            }
+        }
+    }
+
+    // Broadcasts (slave 0) are carried out but never answered, so that
+    // slaves on the same bus don't all reply at once.
+    fn encode(&mut self, item: ModbusRTUResponse, into: &mut Vec<u8>) -> io::Result<()> {
+        if item.slave == 0 {
+            return Ok(());
+        }
+        let mut frame: Vec<u8> = Vec::new();
+        frame.write_u8(item.slave)?;
+        frame.write(item.pdu.encode().as_slice())?;
+        let crc = crc16(&frame);
+        frame.write_u16::<LittleEndian>(crc)?;
+        into.write(frame.as_slice())?;
+        Ok(())
+    }
+}
+
+// RTU frames carried over a stream without an MBAP header, as forwarded by
+// most serial-to-Ethernet converters.
+#[derive(Default)]
+pub struct ModbusRTUProto {
+    diagnostics: Arc<Diagnostics>
+}
+
+impl ModbusRTUProto {
+    pub fn new(diagnostics: Arc<Diagnostics>) -> ModbusRTUProto {
+        ModbusRTUProto { diagnostics: diagnostics }
+    }
+}
+
+impl<T: Io + 'static> ServerProto<T> for ModbusRTUProto {
+    type Request = ModbusRTURequest;
+    type Response = ModbusRTUResponse;
+    type Transport = Framed<T, ModbusRTUCodec>;
+    type BindTransport = ::std::result::Result<Self::Transport,io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(ModbusRTUCodec::new(self.diagnostics.clone())))
+    }
+}
@@ -0,0 +1,210 @@
+// Values that change over time, for testing HMIs against something that
+// looks alive. Each signal drives one address of one table with a
+// waveform, and the simulation writes them all on every tick.
+//
+// Periodic waveforms repeat every `period` seconds, counted from the time
+// the simulation started, moving between `min` and `max`:
+//
+//   sine    starts at the middle, rising;
+//   ramp    climbs from min to max, then drops back to min;
+//   square  is max for the first half of each period and min for the rest.
+//
+// The others:
+//
+//   counter      adds `step` every `interval` seconds, going back to `min`
+//                after passing `max`;
+//   random walk  moves up or down by at most `step` on every tick, staying
+//                within min and max;
+//   steps        holds each value in turn for `interval` seconds, over and
+//                over.
+//
+// Coils and discrete inputs are on for any value but 0. Writes are the
+// application's, as through a RegistersHandle, so they skip the access
+// rules and hooks, and simulating holding registers or coils overwrites
+// what masters write to them.
+
+use std::error;
+use std::f64::consts::PI;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use modbus;
+
+use {Address, Table, Value};
+use block::BlankRegisters;
+use handle::RegistersHandle;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Waveform {
+    Sine { min: Value, max: Value, period: f64 },
+    Ramp { min: Value, max: Value, period: f64 },
+    Square { min: Value, max: Value, period: f64 },
+    Counter { min: Value, max: Value, step: Value, interval: f64 },
+    RandomWalk { min: Value, max: Value, step: Value },
+    Steps { values: Vec<Value>, interval: f64 }
+}
+
+// Why `add` refused a waveform.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum WaveformError {
+    MinAboveMax,
+    // A period or interval that isn't a number of seconds more than 0.
+    NotPositive,
+    NoValues
+}
+
+impl fmt::Display for WaveformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", error::Error::description(self))
+    }
+}
+
+impl error::Error for WaveformError {
+    fn description(&self) -> &str {
+        match *self {
+            WaveformError::MinAboveMax => "min is more than max",
+            WaveformError::NotPositive => "periods and intervals must be more than 0 seconds",
+            WaveformError::NoValues => "steps need at least one value",
+        }
+    }
+}
+
+impl Waveform {
+    fn check(&self) -> Result<(), WaveformError> {
+        let (min, max, seconds) = match *self {
+            Waveform::Sine { min, max, period } |
+            Waveform::Ramp { min, max, period } |
+            Waveform::Square { min, max, period } => (min, max, period),
+            Waveform::Counter { min, max, interval, .. } => (min, max, interval),
+            Waveform::RandomWalk { min, max, .. } => (min, max, 1.0),
+            Waveform::Steps { ref values, interval } => {
+                if values.is_empty() {
+                    return Err(WaveformError::NoValues);
+                }
+                (0, 0, interval)
+            }
+        };
+        if min > max {
+            Err(WaveformError::MinAboveMax)
+        } else if !(seconds > 0.0 && seconds.is_finite()) {
+            Err(WaveformError::NotPositive)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Signal {
+    table: Table,
+    address: Address,
+    waveform: Waveform,
+    // The random walk's last value.
+    value: Value
+}
+
+#[derive(Debug,Clone)]
+pub struct Simulation {
+    signals: Vec<Signal>,
+    // xorshift64 state for the random walks; never 0.
+    random: u64
+}
+
+impl Default for Simulation {
+    fn default() -> Simulation {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() ^ since.subsec_nanos() as u64)
+            .unwrap_or(0);
+        Simulation { signals: Vec::new(), random: nanos | 1 }
+    }
+}
+
+fn scale(min: Value, max: Value, fraction: f64) -> Value {
+    (min as f64 + (max as f64 - min as f64) * fraction).round() as Value
+}
+
+impl Simulation {
+
+    pub fn new() -> Simulation {
+        Simulation::default()
+    }
+
+    // Periods and intervals are in seconds.
+    pub fn add(&mut self, table: Table, address: Address, waveform: Waveform) -> Result<(), WaveformError> {
+        waveform.check()?;
+        let value = match waveform {
+            Waveform::RandomWalk { min, max, .. } => scale(min, max, 0.5),
+            _ => 0
+        };
+        self.signals.push(Signal { table: table, address: address, waveform: waveform, value: value });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signals.is_empty()
+    }
+
+    fn random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    // Each signal's value `elapsed` seconds after the start.
+    fn values(&mut self, elapsed: f64) -> Vec<Value> {
+        let mut values = Vec::with_capacity(self.signals.len());
+        for i in 0..self.signals.len() {
+            let value = match self.signals[i].waveform {
+                Waveform::Sine { min, max, period } =>
+                    scale(min, max, 0.5 + 0.5 * (2.0 * PI * elapsed / period).sin()),
+                Waveform::Ramp { min, max, period } =>
+                    scale(min, max, (elapsed / period).fract()),
+                Waveform::Square { min, max, period } =>
+                    if (elapsed / period).fract() < 0.5 { max } else { min },
+                Waveform::Counter { min, max, step, interval } => {
+                    let range = max as u64 - min as u64 + 1;
+                    let steps = (elapsed / interval) as u64 % range;
+                    min + (steps * step as u64 % range) as Value
+                },
+                Waveform::RandomWalk { min, max, step } => {
+                    let span = 2 * step as u64 + 1;
+                    let delta = (self.random() % span) as i64 - step as i64;
+                    let value = self.signals[i].value as i64 + delta;
+                    ::std::cmp::max(min as i64, ::std::cmp::min(max as i64, value)) as Value
+                },
+                Waveform::Steps { ref values, interval } =>
+                    values[(elapsed / interval) as usize % values.len()]
+            };
+            self.signals[i].value = value;
+            values.push(value);
+        }
+        values
+    }
+
+    // Signals for addresses the block doesn't have are skipped.
+    pub fn step(&mut self, block: &mut BlankRegisters, elapsed: Duration) {
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let values = self.values(elapsed);
+        for (signal, value) in self.signals.iter().zip(values) {
+            let coil = if value == 0 { modbus::Coil::Off } else { modbus::Coil::On };
+            match signal.table {
+                Table::Coils => block.set_coils(signal.address, &[coil]),
+                Table::DiscreteInputs => block.set_discrete_inputs(signal.address, &[coil]),
+                Table::HoldingRegisters => block.set_holding_registers(signal.address, &[value]),
+                Table::InputRegisters => block.set_input_registers(signal.address, &[value])
+            };
+        }
+    }
+
+    // Steps the simulation every `tick`, for good. Run it on a thread of
+    // its own.
+    pub fn run(mut self, handle: RegistersHandle, tick: Duration) {
+        let start = Instant::now();
+        loop {
+            handle.update(|block| self.step(block, start.elapsed()));
+            thread::sleep(tick);
+        }
+    }
+}
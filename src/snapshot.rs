@@ -0,0 +1,231 @@
+// The contents of a block's four tables saved to a file, so that a
+// simulator can be restarted where it left off.
+//
+// The file starts with "MBSS" and a version byte. Then, for coils,
+// discrete inputs, holding registers and input registers in turn, come the
+// table's first address (2 bytes) and size (4 bytes, since a table can
+// hold all 65536 addresses), then its contents: bits packed eight to a
+// byte, lowest address in the lowest bit, as in Modbus responses, or two
+// bytes per register. Last is the CRC of everything before it, as used by
+// RTU. Numbers are big-endian.
+//
+// Files are replaced whole: a snapshot is written to a temporary file next
+// to the target, synced, and renamed over it, so that a crash leaves
+// either the old snapshot or the new one.
+
+use std::error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use futures::Async;
+use futures::executor::{self, Notify};
+use modbus;
+
+use {Address, Values};
+use handle::RegistersHandle;
+use rtu::crc16;
+
+const MAGIC: &'static [u8] = b"MBSS";
+const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // Not a snapshot, or a damaged one.
+    Corrupt,
+    // The snapshot's tables don't start and end where the block's do.
+    Mismatch
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref e) => write!(f, "can't read snapshot: {}", e),
+            SnapshotError::Corrupt => write!(f, "snapshot is damaged"),
+            SnapshotError::Mismatch => write!(f, "snapshot doesn't match the register map"),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn description(&self) -> &str {
+        match *self {
+            SnapshotError::Io(_) => "can't read snapshot",
+            SnapshotError::Corrupt => "snapshot is damaged",
+            SnapshotError::Mismatch => "snapshot doesn't match the register map",
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+// Each table as its first address and contents.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Snapshot {
+    pub coils: (Address, Vec<modbus::Coil>),
+    pub discrete_inputs: (Address, Vec<modbus::Coil>),
+    pub holding_registers: (Address, Values),
+    pub input_registers: (Address, Values)
+}
+
+fn encode_bits(out: &mut Vec<u8>, &(start, ref bits): &(Address, Vec<modbus::Coil>)) {
+    out.write_u16::<BigEndian>(start).unwrap();
+    out.write_u32::<BigEndian>(bits.len() as u32).unwrap();
+    for chunk in bits.chunks(8) {
+        let mut byte = 0u8;
+        for (i, &bit) in chunk.iter().enumerate() {
+            if bit == modbus::Coil::On {
+                byte |= 1 << i;
+            }
+        }
+        out.push(byte);
+    }
+}
+
+fn encode_registers(out: &mut Vec<u8>, &(start, ref registers): &(Address, Values)) {
+    out.write_u16::<BigEndian>(start).unwrap();
+    out.write_u32::<BigEndian>(registers.len() as u32).unwrap();
+    for &register in registers {
+        out.write_u16::<BigEndian>(register).unwrap();
+    }
+}
+
+// Reads the first address and size of a table, leaving `data` at its
+// contents, which take up `content_len(size)` bytes.
+fn decode_table(data: &mut &[u8], content_len: fn(usize) -> usize) -> Result<(Address, usize, usize), SnapshotError> {
+    if data.len() < 6 {
+        return Err(SnapshotError::Corrupt);
+    }
+    let start = BigEndian::read_u16(&data[0..2]);
+    let size = BigEndian::read_u32(&data[2..6]) as usize;
+    if start as usize + size > 0x10000 {
+        return Err(SnapshotError::Corrupt);
+    }
+    let len = content_len(size);
+    *data = &data[6..];
+    if data.len() < len {
+        return Err(SnapshotError::Corrupt);
+    }
+    Ok((start, size, len))
+}
+
+fn decode_bits(data: &mut &[u8]) -> Result<(Address, Vec<modbus::Coil>), SnapshotError> {
+    let (start, size, len) = decode_table(data, |size| (size + 7) / 8)?;
+    let bits = (0..size)
+        .map(|i| if data[i / 8] & (1 << (i % 8)) != 0 { modbus::Coil::On } else { modbus::Coil::Off })
+        .collect();
+    *data = &data[len..];
+    Ok((start, bits))
+}
+
+fn decode_registers(data: &mut &[u8]) -> Result<(Address, Values), SnapshotError> {
+    let (start, _, len) = decode_table(data, |size| size * 2)?;
+    let registers = data[..len].chunks(2).map(BigEndian::read_u16).collect();
+    *data = &data[len..];
+    Ok((start, registers))
+}
+
+impl Snapshot {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        encode_bits(&mut out, &self.coils);
+        encode_bits(&mut out, &self.discrete_inputs);
+        encode_registers(&mut out, &self.holding_registers);
+        encode_registers(&mut out, &self.input_registers);
+        let crc = crc16(&out);
+        out.write_u16::<BigEndian>(crc).unwrap();
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if data.len() < MAGIC.len() + 3 || &data[..MAGIC.len()] != MAGIC || data[MAGIC.len()] != VERSION {
+            return Err(SnapshotError::Corrupt);
+        }
+        let (body, crc) = data.split_at(data.len() - 2);
+        if crc16(body) != BigEndian::read_u16(crc) {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut rest = &body[MAGIC.len() + 1..];
+        let snapshot = Snapshot {
+            coils: decode_bits(&mut rest)?,
+            discrete_inputs: decode_bits(&mut rest)?,
+            holding_registers: decode_registers(&mut rest)?,
+            input_registers: decode_registers(&mut rest)?
+        };
+        if !rest.is_empty() {
+            return Err(SnapshotError::Corrupt);
+        }
+        Ok(snapshot)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = OsString::from(path.as_os_str());
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        {
+            let mut file = File::create(&temp)?;
+            file.write_all(&self.encode())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp, path)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot, SnapshotError> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Snapshot::decode(&data)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum SavePolicy {
+    // Whatever changed, masters or the application.
+    Every(Duration),
+    // After changes masters make, once for all those that came in during
+    // the last save. Changes the application makes through the handle
+    // aren't seen.
+    OnWrite
+}
+
+// For checking on changes without waiting for them.
+struct Unwatched;
+
+impl Notify for Unwatched {
+    fn notify(&self, _: usize) {}
+}
+
+// Saves the block to `path` as the policy says, until a save fails. Run it
+// on a thread of its own.
+pub fn keep_saved<P: AsRef<Path>>(handle: RegistersHandle, path: P, policy: SavePolicy) -> io::Result<()> {
+    let path = path.as_ref();
+    match policy {
+        SavePolicy::Every(interval) => loop {
+            thread::sleep(interval);
+            handle.update(|block| block.snapshot()).save(path)?;
+        },
+        SavePolicy::OnWrite => {
+            let mut changes = executor::spawn(handle.update(|block| block.subscribe()));
+            let unwatched = Arc::new(Unwatched);
+            while let Some(_) = changes.wait_stream() {
+                while let Ok(Async::Ready(Some(_))) = changes.poll_stream_notify(&unwatched, 0) {}
+                handle.update(|block| block.snapshot()).save(path)?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,286 @@
+// A block that only holds the address ranges declared for it, for devices
+// whose register maps have holes. Any request that touches a hole, even if
+// it starts and ends in a declared range, fails with IllegalDataAddress.
+
+use std::collections::BTreeMap;
+
+use modbus::{self, binary};
+use {Code, Address, Quantity, Value, Values};
+use ModbusResponsePDU;
+use block::ModbusBlock;
+
+// The declared ranges of one table, keyed by start address. Ranges never
+// overlap or touch; declaring one that does merges them.
+#[derive(Debug,Clone)]
+struct Segments<T> {
+    segments : BTreeMap<usize, Vec<T>>
+}
+
+impl<T> Default for Segments<T> {
+    fn default() -> Segments<T> {
+        Segments { segments: BTreeMap::new() }
+    }
+}
+
+impl<T: Clone> Segments<T> {
+
+    fn add(&mut self, start: Address, size: usize, value: T) {
+        let mut start = start as usize;
+        let mut end = ::std::cmp::min(start + size, 0x10000);
+        if end <= start {
+            return;
+        }
+        let merged: Vec<usize> = self.segments.iter()
+            .filter(|&(&s, values)| s <= end && s + values.len() >= start)
+            .map(|(&s, _)| s)
+            .collect();
+        for &s in &merged {
+            start = ::std::cmp::min(start, s);
+            end = ::std::cmp::max(end, s + self.segments[&s].len());
+        }
+        let mut values = vec![value; end - start];
+        // Keep what the merged ranges held.
+        for s in merged {
+            let old = self.segments.remove(&s).unwrap();
+            values[s - start..s - start + old.len()].clone_from_slice(&old);
+        }
+        self.segments.insert(start, values);
+    }
+
+    // The segment holding all `len` entries from `address`, and where they
+    // begin in it.
+    fn find(&self, address: Address, len: usize) -> Option<(usize, usize)> {
+        let address = address as usize;
+        match self.segments.range(..address + 1).next_back() {
+            Some((&s, values)) if address + len <= s + values.len() => {
+                Some((s, address - s))
+            },
+            _ => None
+        }
+    }
+
+    fn read(&self, address: Address, quantity: Quantity) -> Option<Vec<T>> {
+        self.find(address, quantity as usize).map(|(s, offset)| {
+            self.segments[&s][offset..offset + quantity as usize].to_vec()
+        })
+    }
+
+    fn write(&mut self, address: Address, values: &[T]) -> bool {
+        match self.find(address, values.len()) {
+            Some((s, offset)) => {
+                let segment = self.segments.get_mut(&s).unwrap();
+                segment[offset..offset + values.len()].clone_from_slice(values);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn get(&self, address: Address) -> Option<T> {
+        self.read(address, 1).map(|mut values| values.remove(0))
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct SparseRegisters {
+    coils : Segments<modbus::Coil>,
+    discrete_inputs : Segments<modbus::Coil>,
+    holding_registers : Segments<Value>,
+    input_registers : Segments<Value>
+}
+
+fn exception(code: Code, exception_code: modbus::ExceptionCode) -> ModbusResponsePDU {
+    ModbusResponsePDU::ModbusErrorResponse {
+        code: code | 0x80,
+        exception_code: exception_code as u8
+    }
+}
+
+impl SparseRegisters {
+
+    // Starts out with no addresses at all.
+    pub fn new() -> SparseRegisters {
+        SparseRegisters::default()
+    }
+
+    // Each declares `size` addresses from `start`, set to off or 0.
+    pub fn add_coils(&mut self, start: Address, size: usize) {
+        self.coils.add(start, size, modbus::Coil::Off);
+    }
+
+    pub fn add_discrete_inputs(&mut self, start: Address, size: usize) {
+        self.discrete_inputs.add(start, size, modbus::Coil::Off);
+    }
+
+    pub fn add_holding_registers(&mut self, start: Address, size: usize) {
+        self.holding_registers.add(start, size, 0);
+    }
+
+    pub fn add_input_registers(&mut self, start: Address, size: usize) {
+        self.input_registers.add(start, size, 0);
+    }
+
+    // The setters return false if the address wasn't declared.
+    pub fn set_coil(&mut self, address: Address, value: modbus::Coil) -> bool {
+        self.coils.write(address, &[value])
+    }
+
+    pub fn set_discrete_input(&mut self, address: Address, value: modbus::Coil) -> bool {
+        self.discrete_inputs.write(address, &[value])
+    }
+
+    pub fn set_holding_register(&mut self, address: Address, value: Value) -> bool {
+        self.holding_registers.write(address, &[value])
+    }
+
+    pub fn set_input_register(&mut self, address: Address, value: Value) -> bool {
+        self.input_registers.write(address, &[value])
+    }
+
+    pub fn coil(&self, address: Address) -> Option<modbus::Coil> {
+        self.coils.get(address)
+    }
+
+    pub fn discrete_input(&self, address: Address) -> Option<modbus::Coil> {
+        self.discrete_inputs.get(address)
+    }
+
+    pub fn holding_register(&self, address: Address) -> Option<Value> {
+        self.holding_registers.get(address)
+    }
+
+    pub fn input_register(&self, address: Address) -> Option<Value> {
+        self.input_registers.get(address)
+    }
+}
+
+impl ModbusBlock for SparseRegisters {
+
+    fn read_coils(&mut self, code: Code, address: Address, quantity: Quantity) -> ModbusResponsePDU {
+        if quantity < 1 || quantity > 2000 {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        match self.coils.read(address, quantity) {
+            Some(values) => {
+                let status = binary::pack_bits(&values);
+                ModbusResponsePDU::ReadCoilsResponse {
+                    code: code, byte_count: status.len() as u8, coil_status: status
+                }
+            },
+            None => exception(code, modbus::ExceptionCode::IllegalDataAddress)
+        }
+    }
+
+    fn read_discrete_inputs(&mut self, code: Code, address: Address, quantity: Quantity) -> ModbusResponsePDU {
+        if quantity < 1 || quantity > 2000 {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        match self.discrete_inputs.read(address, quantity) {
+            Some(values) => {
+                let status = binary::pack_bits(&values);
+                ModbusResponsePDU::ReadDiscreteInputsResponse {
+                    code: code, byte_count: status.len() as u8, input_status: status
+                }
+            },
+            None => exception(code, modbus::ExceptionCode::IllegalDataAddress)
+        }
+    }
+
+    fn read_holding_registers(&mut self, code: Code, address: Address, quantity: Quantity) -> ModbusResponsePDU {
+        if quantity < 1 || quantity > 125 {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        match self.holding_registers.read(address, quantity) {
+            Some(values) => ModbusResponsePDU::ReadHoldingRegistersResponse {
+                code: code, byte_count: (2 * quantity) as u8, values: values
+            },
+            None => exception(code, modbus::ExceptionCode::IllegalDataAddress)
+        }
+    }
+
+    fn read_input_registers(&mut self, code: Code, address: Address, quantity: Quantity) -> ModbusResponsePDU {
+        if quantity < 1 || quantity > 125 {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        match self.input_registers.read(address, quantity) {
+            Some(values) => ModbusResponsePDU::ReadInputRegistersResponse {
+                code: code, byte_count: (2 * quantity) as u8, values: values
+            },
+            None => exception(code, modbus::ExceptionCode::IllegalDataAddress)
+        }
+    }
+
+    fn write_single_coil(&mut self, code: Code, address: Address, value: Value) -> ModbusResponsePDU {
+        let coil = match value {
+            0xFF00 => modbus::Coil::On,
+            0x0000 => modbus::Coil::Off,
+            _ => return exception(code, modbus::ExceptionCode::IllegalDataValue)
+        };
+        if !self.coils.write(address, &[coil]) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        ModbusResponsePDU::WriteSingleCoilResponse { code: code, address: address, value: value }
+    }
+
+    fn write_single_register(&mut self, code: Code, address: Address, value: Value) -> ModbusResponsePDU {
+        if !self.holding_registers.write(address, &[value]) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        ModbusResponsePDU::WriteSingleRegisterResponse { code: code, address: address, value: value }
+    }
+
+    fn write_multiple_coils(&mut self, code: Code, address: Address, quantity: Quantity,
+                            values: Vec<modbus::Coil>) -> ModbusResponsePDU {
+        if quantity < 1 || quantity > 0x07B0 {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        if !self.coils.write(address, &values[..quantity as usize]) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        ModbusResponsePDU::WriteMultipleCoilsResponse { code: code, address: address, quantity: quantity }
+    }
+
+    fn write_multiple_registers(&mut self, code: Code, address: Address, quantity: Quantity,
+                                values: Values) -> ModbusResponsePDU {
+        if quantity < 1 || quantity > 0x007B {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        if !self.holding_registers.write(address, &values[..quantity as usize]) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        ModbusResponsePDU::WriteMultipleRegistersResponse { code: code, address: address, quantity: quantity }
+    }
+
+    // Both ranges are checked before anything is written.
+    fn read_write_multiple_registers(&mut self, code: Code,
+                                     read_address: Address, read_quantity: Quantity,
+                                     write_address: Address, write_quantity: Quantity,
+                                     values: Values) -> ModbusResponsePDU {
+        if read_quantity < 1 || read_quantity > 0x007D ||
+            write_quantity < 1 || write_quantity > 0x0079 ||
+            values.len() != write_quantity as usize {
+            return exception(code, modbus::ExceptionCode::IllegalDataValue);
+        }
+        if self.holding_registers.find(read_address, read_quantity as usize).is_none() ||
+            !self.holding_registers.write(write_address, &values) {
+            return exception(code, modbus::ExceptionCode::IllegalDataAddress);
+        }
+        let read = self.holding_registers.read(read_address, read_quantity).unwrap();
+        ModbusResponsePDU::ReadWriteMultipleRegistersResponse {
+            code: code, byte_count: (2 * read_quantity) as u8, values: read
+        }
+    }
+
+    fn mask_write_register(&mut self, code: Code, address: Address,
+                           and_mask: Value, or_mask: Value) -> ModbusResponsePDU {
+        match self.holding_registers.get(address) {
+            Some(current) => {
+                self.holding_registers.write(address, &[(current & and_mask) | (or_mask & !and_mask)]);
+                ModbusResponsePDU::MaskWriteRegisterResponse {
+                    code: code, address: address, and_mask: and_mask, or_mask: or_mask
+                }
+            },
+            None => exception(code, modbus::ExceptionCode::IllegalDataAddress)
+        }
+    }
+}
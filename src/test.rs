@@ -1,13 +1,57 @@
 //
 
 use BlankRegisters;
+use ModbusBlock;
+use SparseRegisters;
+use config::{Config, ConfigError};
+use values::{RegisterOrder, RegisterValue};
+use access::{Access, AccessRules};
+use Table;
+use hooks::WriteResult;
+use changes::Change;
+use handle::RegistersHandle;
+use snapshot::{Snapshot, SnapshotError};
+use simulation::{Simulation, Waveform, WaveformError};
 use ModbusRequestPDU;
 use ModbusResponsePDU;
+use ModbusFooter;
+use identification::{self, DeviceIdentification};
+use diagnostics::Diagnostics;
 use FunctionCode;
+use rtu::{crc16, ModbusRTUCodec, ModbusRTUResponse};
+use ascii::{lrc, ModbusASCIICodec, ModbusASCIIResponse};
+use udp::{serve_udp, ModbusUDPCodec};
+use {ModbusTCPCodec, ModbusTCPRequest, ModbusTCPResponse};
 
 #[cfg(test)]
 mod tests {
-    use super::{BlankRegisters,ModbusRequestPDU,ModbusResponsePDU,FunctionCode};
+    use super::SparseRegisters;
+    use super::{Config, ConfigError};
+    use super::{RegisterOrder, RegisterValue};
+    use super::{Access, AccessRules, Table};
+    use super::WriteResult;
+    use super::Change;
+    use super::RegistersHandle;
+    use super::{Snapshot, SnapshotError};
+    use super::{Simulation, Waveform, WaveformError};
+    use std::time::Duration;
+    use std::thread;
+    use futures::Stream;
+    use std::sync::{Arc, Mutex};
+    use super::{BlankRegisters,ModbusBlock,ModbusRequestPDU,ModbusResponsePDU,FunctionCode};
+    use super::ModbusFooter;
+    use super::{identification, DeviceIdentification};
+    use super::Diagnostics;
+    use super::{crc16, ModbusRTUCodec, ModbusRTUResponse};
+    use super::{lrc, ModbusASCIICodec, ModbusASCIIResponse};
+    use super::{serve_udp, ModbusUDPCodec};
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+    use futures::{future, Future};
+    use std::io;
+    use super::{ModbusTCPCodec, ModbusTCPRequest, ModbusTCPResponse};
+    use tokio_core::net::UdpCodec;
+    use tokio_core::io::{Codec, EasyBuf};
     #[test]
     fn test_read_coils(){
         let mut br = BlankRegisters::new();
@@ -35,4 +79,952 @@ mod tests {
         };
         
     }
+
+    #[test]
+    fn test_rtu_crc16(){
+        // Read Holding Registers, slave 1, address 0, quantity 10.
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xCDC5);
+    }
+
+    #[test]
+    fn test_rtu_decode_partial_and_bad_crc(){
+        let mut codec = ModbusRTUCodec::default();
+        // A frame with a corrupted CRC, followed by the first half of a
+        // valid one.
+        let mut buf = EasyBuf::from(vec![0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0x00,
+                                         0x01, 0x03, 0x00, 0x00]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.get_mut().extend_from_slice(&[0x00, 0x0A, 0xC5, 0xCD]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.slave, 1);
+        assert_eq!(req.pdu.code, FunctionCode::ReadHoldingRegisters as u8);
+        assert_eq!(req.pdu.address, 0);
+        assert_eq!(req.pdu.q_or_v, 10);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_rtu_decode_split_unknown_function(){
+        let mut codec = ModbusRTUCodec::default();
+        // Function 0x41 doesn't tell the length, so the frame ends where
+        // the CRC checks out, however many reads it takes to arrive.
+        let mut frame = vec![0x01, 0x41, 0x00, 0x00, 0xA5, 0x37];
+        let crc = crc16(&frame);
+        frame.push((crc & 0xff) as u8);
+        frame.push((crc >> 8) as u8);
+        let mut buf = EasyBuf::from(frame[..5].to_vec());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.get_mut().extend_from_slice(&frame[5..]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((req.slave, req.pdu.code), (1, 0x41));
+        assert_eq!(buf.len(), 0);
+        // Without a CRC match in a whole frame's worth of bytes, the first
+        // is skipped.
+        let mut buf = EasyBuf::from(vec![0x41; 255]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 255);
+        buf.get_mut().push(0x41);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.len() < 256);
+    }
+
+    fn rtu_frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = body.to_vec();
+        let crc = crc16(&frame);
+        frame.push((crc & 0xff) as u8);
+        frame.push((crc >> 8) as u8);
+        frame
+    }
+
+    #[test]
+    fn test_rtu_bad_unknown_frame_then_good_one(){
+        let br = BlankRegisters::new();
+        let mut codec = ModbusRTUCodec::new(br.diagnostics());
+        // A corrupted frame for a function with no known length mustn't
+        // hold up the request after it.
+        let mut bytes = rtu_frame(&[0x01, 0x41, 0x00, 0x00, 0xA5, 0x37]);
+        bytes[7] ^= 0xFF;
+        bytes.extend_from_slice(&rtu_frame(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]));
+        let mut buf = EasyBuf::from(bytes);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((req.slave, req.pdu.code, req.pdu.q_or_v), (1, 0x03, 10));
+        assert_eq!(buf.len(), 0);
+        assert_eq!(br.diagnostics().bus_communication_error_count(), 1);
+        // A frame with a good CRC that doesn't parse is an error too.
+        let mut buf = EasyBuf::from(rtu_frame(&[0x01, 0x2B]));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 0);
+        assert_eq!(br.diagnostics().bus_communication_error_count(), 2);
+    }
+
+    #[test]
+    fn test_rtu_split_return_query_data(){
+        let mut br = BlankRegisters::new();
+        let mut codec = ModbusRTUCodec::new(br.diagnostics());
+        let mut frame = vec![0x11, 0x08, 0x00, 0x00, 0xA5, 0x37, 0x12];
+        let crc = crc16(&frame);
+        frame.push((crc & 0xff) as u8);
+        frame.push((crc >> 8) as u8);
+        let mut buf = EasyBuf::from(frame[..4].to_vec());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.get_mut().extend_from_slice(&frame[4..]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        let resp = ModbusRTUResponse { slave: req.slave, pdu: br.call(req.pdu) };
+        let mut out = Vec::new();
+        codec.encode(resp, &mut out).unwrap();
+        assert_eq!(out, frame);
+        assert_eq!(br.diagnostics().bus_communication_error_count(), 0);
+    }
+
+    #[test]
+    fn test_rtu_round_trip(){
+        let mut br = BlankRegisters::new();
+        let mut codec = ModbusRTUCodec::default();
+        // Write Multiple Registers, slave 0x11, 2 registers at 1.
+        let mut frame = vec![0x11, 0x10, 0x00, 0x01, 0x00, 0x02, 0x04,
+                             0x00, 0x0A, 0x01, 0x02];
+        let crc = crc16(&frame);
+        frame.push((crc & 0xff) as u8);
+        frame.push((crc >> 8) as u8);
+        let mut buf = EasyBuf::from(frame);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        let resp = ModbusRTUResponse { slave: req.slave, pdu: br.call(req.pdu) };
+        let mut out = Vec::new();
+        codec.encode(resp, &mut out).unwrap();
+        assert_eq!(&out[..6], &[0x11, 0x10, 0x00, 0x01, 0x00, 0x02]);
+        let crc = crc16(&out[..6]);
+        assert_eq!(&out[6..], &[(crc & 0xff) as u8, (crc >> 8) as u8]);
+    }
+
+    #[test]
+    fn test_rtu_broadcast_not_answered(){
+        let mut br = BlankRegisters::new();
+        let mut codec = ModbusRTUCodec::default();
+        let mut frame = vec![0x00, 0x06, 0x00, 0x01, 0x12, 0x34];
+        let crc = crc16(&frame);
+        frame.push((crc & 0xff) as u8);
+        frame.push((crc >> 8) as u8);
+        let mut buf = EasyBuf::from(frame);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        let resp = ModbusRTUResponse { slave: req.slave, pdu: br.call_from(None, req.slave, req.pdu) };
+        let mut out = Vec::new();
+        codec.encode(resp, &mut out).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(br.holding_registers(1, 1), Some(vec![0x1234]));
+        // Logged as a broadcast, with nothing sent.
+        let out = tcp_round_trip(&mut br, &[0x0C]);
+        assert_eq!(&out[7..], &[0x0C, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x80, 0xC0]);
+        // Not answered, and a broadcast exception isn't counted as one.
+        let mut buf = EasyBuf::from(rtu_frame(&[0x00, 0x03, 0x00, 0x00, 0x00, 0x7E]));
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        br.call_from(None, req.slave, req.pdu);
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0F, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0F, 0x00, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0D, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0D, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_ascii_decode_partial_and_bad_lrc(){
+        assert_eq!(lrc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xF2);
+        let mut codec = ModbusASCIICodec::default();
+        let mut buf = EasyBuf::from(b"noise:01030000000A00\r\n:0103".to_vec());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.get_mut().extend_from_slice(b"0000000af2\r\n");
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.slave, 1);
+        assert_eq!(req.pdu.code, FunctionCode::ReadHoldingRegisters as u8);
+        assert_eq!(req.pdu.q_or_v, 10);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_ascii_encode(){
+        let mut codec = ModbusASCIICodec::default();
+        let mut out = Vec::new();
+        codec.encode(ModbusASCIIResponse {
+            slave: 0x11,
+            pdu: ModbusResponsePDU::WriteSingleRegisterResponse {
+                code: 0x06, address: 0x0001, value: 0x0003 }
+        }, &mut out).unwrap();
+        assert_eq!(out, b":110600010003E5\r\n".to_vec());
+        out.clear();
+        codec.encode(ModbusASCIIResponse {
+            slave: 0x00,
+            pdu: ModbusResponsePDU::WriteSingleRegisterResponse {
+                code: 0x06, address: 0x0001, value: 0x0003 }
+        }, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_udp_decode(){
+        let mut codec = ModbusUDPCodec::default();
+        let peer = "127.0.0.1:5020".parse().unwrap();
+        let (addr, req) = codec.decode(&peer, &[0x12, 0x34, 0x00, 0x00, 0x00, 0x06, 0x01,
+                                               0x04, 0x00, 0x08, 0x00, 0x01]).unwrap();
+        let req = req.unwrap();
+        assert_eq!(addr, peer);
+        assert_eq!({ req.header.tid }, 0x1234);
+        assert_eq!(req.header.uid, 1);
+        assert_eq!(req.pdu.code, FunctionCode::ReadInputRegisters as u8);
+        // Truncated datagrams are dropped rather than ending the stream.
+        let (_, req) = codec.decode(&peer, &[0x12, 0x34, 0x00, 0x00, 0x00, 0x06, 0x01,
+                                             0x04, 0x00]).unwrap();
+        assert!(req.is_none());
+    }
+
+    struct BlockService(Arc<Mutex<BlankRegisters>>, Option<::std::net::SocketAddr>);
+
+    impl Service for BlockService {
+        type Request = ModbusTCPRequest;
+        type Response = ModbusTCPResponse;
+        type Error = io::Error;
+        type Future = future::FutureResult<ModbusTCPResponse, io::Error>;
+
+        fn call(&self, req: ModbusTCPRequest) -> Self::Future {
+            let pdu = self.0.lock().unwrap().call_from(self.1, req.header.uid, req.pdu);
+            future::ok(ModbusTCPResponse { header: req.header, pdu: pdu })
+        }
+    }
+
+    #[test]
+    fn test_serve_udp(){
+        let mut core = Core::new().unwrap();
+        let socket = ::tokio_core::net::UdpSocket::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let block = Arc::new(Mutex::new(BlankRegisters::new()));
+        block.lock().unwrap().set_input_registers(8, &[0x0102]);
+        let diagnostics = block.lock().unwrap().diagnostics();
+        let changes = block.lock().unwrap().subscribe();
+        let service_block = block.clone();
+        core.handle().spawn(serve_udp(socket, move |peer| BlockService(service_block.clone(), Some(peer)),
+                                      diagnostics).map_err(|_| ()));
+        let client = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_nonblocking(true).unwrap();
+        let mut receive = |core: &mut Core| {
+            let mut buf = [0; 260];
+            for _ in 0..100 {
+                core.turn(Some(::std::time::Duration::from_millis(10)));
+                if let Ok((n, _)) = client.recv_from(&mut buf) {
+                    return Some(buf[..n].to_vec());
+                }
+            }
+            None
+        };
+        // A truncated datagram is dropped, and the next one still answered.
+        client.send_to(&[0x12, 0x34, 0x00, 0x00, 0x00, 0x06, 0x01, 0x04], addr).unwrap();
+        client.send_to(&[0x12, 0x35, 0x00, 0x00, 0x00, 0x06, 0x01,
+                         0x04, 0x00, 0x08, 0x00, 0x01], addr).unwrap();
+        assert_eq!(receive(&mut core), Some(vec![0x12, 0x35, 0x00, 0x00, 0x00, 0x05, 0x01,
+                                                 0x04, 0x02, 0x01, 0x02]));
+        // Changes name the master and the unit id it wrote to.
+        client.send_to(&[0x12, 0x36, 0x00, 0x00, 0x00, 0x06, 0x07,
+                         0x06, 0x00, 0x02, 0x00, 0x2A], addr).unwrap();
+        assert!(receive(&mut core).is_some());
+        let change = changes.wait().next().unwrap().unwrap();
+        assert_eq!(change.unit_id, Some(0x07));
+        assert_eq!(change.peer, Some(client.local_addr().unwrap()));
+        assert_eq!(change.new_values, vec![0x2A]);
+    }
+
+    #[test]
+    fn test_tcp_unknown_function(){
+        let mut br = BlankRegisters::new();
+        let mut codec = ModbusTCPCodec::default();
+        let mut buf = EasyBuf::from(vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01,
+                                         0x42, 0x00]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        match br.call(req.pdu) {
+            ModbusResponsePDU::ModbusErrorResponse { code, exception_code } => {
+                assert_eq!(code, 0xC2);
+                assert_eq!(exception_code, 0x01);
+            },
+            _ => assert!(false)
+        }
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_tcp_invalid_mbap(){
+        let mut codec = ModbusTCPCodec::default();
+        // A length that leaves no room for the function code.
+        let mut buf = EasyBuf::from(vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+                                         0x03]);
+        assert!(codec.decode(&mut buf).is_err());
+        // Longer than the 260 byte maximum ADU.
+        let mut buf = EasyBuf::from(vec![0x00, 0x01, 0x00, 0x00, 0x00, 0xFF, 0x01]);
+        assert!(codec.decode(&mut buf).is_err());
+        // Not the Modbus protocol id.
+        let mut buf = EasyBuf::from(vec![0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x01,
+                                         0x03, 0x00, 0x00, 0x00, 0x01]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_tcp_frames_by_mbap_length(){
+        let mut codec = ModbusTCPCodec::default();
+        // Two Read Holding Registers requests, the second one incomplete.
+        let mut buf = EasyBuf::from(vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01,
+                                         0x03, 0x00, 0x00, 0x00, 0x01,
+                                         0x00, 0x02, 0x00, 0x00, 0x00, 0x06, 0x01,
+                                         0x03, 0x00]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!({ req.header.tid }, 1);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.get_mut().extend_from_slice(&[0x00, 0x00, 0x02]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!({ req.header.tid }, 2);
+        assert_eq!(req.pdu.q_or_v, 2);
+    }
+
+    #[test]
+    fn test_write_multiple_registers_short_data(){
+        let mut br = BlankRegisters::new();
+        let req = ModbusRequestPDU {
+            code: FunctionCode::WriteMultipleRegisters as u8,
+            address: 0,
+            q_or_v: 3,
+            addl: Some(ModbusFooter { byte_count: 2, data: vec![0x00, 0x01] })
+        };
+        match br.call(req) {
+            ModbusResponsePDU::ModbusErrorResponse { code, exception_code } => {
+                assert_eq!(code, 0x90);
+                assert_eq!(exception_code, 0x03);
+            },
+            _ => assert!(false)
+        }
+    }
+
+    #[test]
+    fn test_write_multiple_byte_count_exact(){
+        let mut br = BlankRegisters::new();
+        // One byte too many for 3 registers, and for 8 coils.
+        let out = tcp_round_trip(&mut br, &[0x10, 0x00, 0x00, 0x00, 0x03, 0x08,
+                                            0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04]);
+        assert_eq!(&out[7..], &[0x90, 0x03]);
+        let out = tcp_round_trip(&mut br, &[0x0F, 0x00, 0x00, 0x00, 0x08, 0x02, 0xFF, 0x00]);
+        assert_eq!(&out[7..], &[0x8F, 0x03]);
+        assert_eq!(br.holding_registers(0, 1), Some(vec![0]));
+        let out = tcp_round_trip(&mut br, &[0x0F, 0x00, 0x00, 0x00, 0x09, 0x02, 0xFF, 0x01]);
+        assert_eq!(&out[7..], &[0x0F, 0x00, 0x00, 0x00, 0x09]);
+    }
+
+    // Wrap a request PDU in an MBAP header, run it through the codec and
+    // the block, and return the encoded response ADU.
+    fn tcp_round_trip(br: &mut BlankRegisters, pdu: &[u8]) -> Vec<u8> {
+        let mut codec = ModbusTCPCodec::default();
+        let mut frame = vec![0x00, 0x2A, 0x00, 0x00, 0x00, pdu.len() as u8 + 1, 0x11];
+        frame.extend_from_slice(pdu);
+        let mut buf = EasyBuf::from(frame);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        let resp = ModbusTCPResponse { header: req.header.clone(), pdu: br.call(req.pdu) };
+        let mut out = Vec::new();
+        codec.encode(resp, &mut out).unwrap();
+        out
+    }
+
+    // Request/response pairs from the examples in the Modbus Application
+    // Protocol Specification V1.1b.
+    #[test]
+    fn test_tcp_round_trip_spec_vectors(){
+        let mut br = BlankRegisters::new();
+        let vectors: Vec<(&[u8], &[u8])> = vec![
+            (&[0x0F, 0x00, 0x13, 0x00, 0x13, 0x03, 0xCD, 0x6B, 0x05],
+             &[0x0F, 0x00, 0x13, 0x00, 0x13]),
+            (&[0x01, 0x00, 0x13, 0x00, 0x13],
+             &[0x01, 0x03, 0xCD, 0x6B, 0x05]),
+            (&[0x0F, 0x00, 0x13, 0x00, 0x0A, 0x02, 0xCD, 0x01],
+             &[0x0F, 0x00, 0x13, 0x00, 0x0A]),
+            (&[0x05, 0x00, 0xAC, 0xFF, 0x00],
+             &[0x05, 0x00, 0xAC, 0xFF, 0x00]),
+            (&[0x10, 0x00, 0x6B, 0x00, 0x03, 0x06, 0x02, 0x2B, 0x00, 0x00, 0x00, 0x64],
+             &[0x10, 0x00, 0x6B, 0x00, 0x03]),
+            (&[0x03, 0x00, 0x6B, 0x00, 0x03],
+             &[0x03, 0x06, 0x02, 0x2B, 0x00, 0x00, 0x00, 0x64]),
+            (&[0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02],
+             &[0x10, 0x00, 0x01, 0x00, 0x02]),
+            (&[0x06, 0x00, 0x01, 0x00, 0x03],
+             &[0x06, 0x00, 0x01, 0x00, 0x03]),
+            (&[0x04, 0x00, 0x08, 0x00, 0x01],
+             &[0x04, 0x02, 0x00, 0x00]),
+            (&[0x02, 0x00, 0xC4, 0x00, 0x16],
+             &[0x02, 0x03, 0x00, 0x00, 0x00]),
+            (&[0x10, 0x00, 0x03, 0x00, 0x06, 0x0C, 0x00, 0xFE, 0x0A, 0xCD, 0x00, 0x01,
+               0x00, 0x03, 0x00, 0x0D, 0x00, 0xFF],
+             &[0x10, 0x00, 0x03, 0x00, 0x06]),
+            (&[0x17, 0x00, 0x03, 0x00, 0x06, 0x00, 0x0E, 0x00, 0x03, 0x06,
+               0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF],
+             &[0x17, 0x0C, 0x00, 0xFE, 0x0A, 0xCD, 0x00, 0x01, 0x00, 0x03, 0x00, 0x0D,
+               0x00, 0xFF]),
+            (&[0x03, 0x00, 0x0E, 0x00, 0x03],
+             &[0x03, 0x06, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF]),
+            (&[0x06, 0x00, 0x04, 0x00, 0x12],
+             &[0x06, 0x00, 0x04, 0x00, 0x12]),
+            (&[0x16, 0x00, 0x04, 0x00, 0xF2, 0x00, 0x25],
+             &[0x16, 0x00, 0x04, 0x00, 0xF2, 0x00, 0x25]),
+            (&[0x03, 0x00, 0x04, 0x00, 0x01],
+             &[0x03, 0x02, 0x00, 0x17]),
+        ];
+        for (request, response) in vectors {
+            let out = tcp_round_trip(&mut br, request);
+            let mut expected = vec![0x00, 0x2A, 0x00, 0x00, 0x00, response.len() as u8 + 1, 0x11];
+            expected.extend_from_slice(response);
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn test_tcp_response_length_for_exception(){
+        let mut br = BlankRegisters::new();
+        // Quantity 126 is over the limit for FC 3.
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0x00, 0x00, 0x7E]);
+        assert_eq!(out, vec![0x00, 0x2A, 0x00, 0x00, 0x00, 0x03, 0x11, 0x83, 0x03]);
+    }
+
+    #[test]
+    fn test_read_write_multiple_registers_limits(){
+        let mut br = BlankRegisters::new();
+        // Byte count disagrees with the write quantity.
+        let out = tcp_round_trip(&mut br, &[0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+                                            0x00, 0x02, 0x02, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x97, 0x03]);
+        // Read range runs off the end of the table.
+        let out = tcp_round_trip(&mut br, &[0x17, 0xFF, 0xFF, 0x00, 0x02, 0x00, 0x00,
+                                            0x00, 0x01, 0x02, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x97, 0x02]);
+    }
+
+    #[test]
+    fn test_read_device_identification_basic(){
+        let mut br = BlankRegisters::new();
+        br.set_device_identification(DeviceIdentification::new("Acme", "PLC1", "V1.0"));
+        let out = tcp_round_trip(&mut br, &[0x2B, 0x0E, 0x01, 0x00]);
+        assert_eq!(&out[7..], &[0x2B, 0x0E, 0x01, 0x81, 0x00, 0x00, 0x03,
+                                0x00, 0x04, b'A', b'c', b'm', b'e',
+                                0x01, 0x04, b'P', b'L', b'C', b'1',
+                                0x02, 0x04, b'V', b'1', b'.', b'0'][..]);
+        // Individual access to an object that doesn't exist.
+        let out = tcp_round_trip(&mut br, &[0x2B, 0x0E, 0x04, 0x05]);
+        assert_eq!(&out[7..], &[0xAB, 0x02]);
+        // Unsupported MEI type.
+        let out = tcp_round_trip(&mut br, &[0x2B, 0x0D, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0xAB, 0x01]);
+    }
+
+    #[test]
+    fn test_read_device_identification_more_follows(){
+        let mut br = BlankRegisters::new();
+        let mut ident = DeviceIdentification::new("Acme", "PLC1", "V1.0");
+        ident.set_object(identification::PRODUCT_NAME, "Test bench");
+        for id in 0x80..0x85 {
+            ident.set_object(id, vec![id; 100]);
+        }
+        br.set_device_identification(ident);
+        let out = tcp_round_trip(&mut br, &[0x2B, 0x0E, 0x03, 0x00]);
+        // Basic objects, the product name and two of the extended ones.
+        assert_eq!(&out[7..14], &[0x2B, 0x0E, 0x03, 0x83, 0xFF, 0x82, 0x06]);
+        let out = tcp_round_trip(&mut br, &[0x2B, 0x0E, 0x03, 0x82]);
+        assert_eq!(&out[7..14], &[0x2B, 0x0E, 0x03, 0x83, 0xFF, 0x84, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x2B, 0x0E, 0x03, 0x84]);
+        assert_eq!(&out[7..14], &[0x2B, 0x0E, 0x03, 0x83, 0x00, 0x00, 0x01]);
+        assert_eq!(out.len(), 7 + 7 + 2 + 100);
+    }
+
+    #[test]
+    fn test_diagnostics_counters(){
+        let mut br = BlankRegisters::new();
+        let mut codec = ModbusRTUCodec::new(br.diagnostics());
+        // One corrupted frame, then a good one.
+        let mut buf = EasyBuf::from(vec![0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0x00,
+                                         0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]);
+        let req = codec.decode(&mut buf).unwrap().unwrap();
+        br.call(req.pdu);
+        // An exception.
+        tcp_round_trip(&mut br, &[0x03, 0x00, 0x00, 0x00, 0x7E]);
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0B, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0B, 0x00, 0x01]);
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0C, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0C, 0x00, 0x01]);
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0D, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0D, 0x00, 0x01]);
+        // Counted before it is answered, so this request is the sixth.
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0E, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0E, 0x00, 0x06]);
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0A, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x0A, 0x00, 0x00]);
+        assert_eq!(br.diagnostics().bus_communication_error_count(), 0);
+        assert_eq!(br.diagnostics().server_message_count(), 0);
+    }
+
+    #[test]
+    fn test_diagnostics_return_query_data(){
+        let mut br = BlankRegisters::new();
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x00, 0xA5, 0x37, 0x12, 0x34]);
+        assert_eq!(&out[7..], &[0x08, 0x00, 0x00, 0xA5, 0x37, 0x12, 0x34]);
+        // Counter requests take no data but 0x0000.
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x0B, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x88, 0x03]);
+        // Force Listen Only Mode isn't supported.
+        let out = tcp_round_trip(&mut br, &[0x08, 0x00, 0x04, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x88, 0x01]);
+    }
+
+    #[test]
+    fn test_serial_line_functions(){
+        let mut br = BlankRegisters::new();
+        br.set_exception_status(0x6D);
+        br.set_server_id("PLC");
+        let out = tcp_round_trip(&mut br, &[0x07]);
+        assert_eq!(&out[7..], &[0x07, 0x6D]);
+        let out = tcp_round_trip(&mut br, &[0x11]);
+        assert_eq!(&out[7..], &[0x11, 0x04, b'P', b'L', b'C', 0xFF]);
+        br.set_run_indicator(false);
+        let out = tcp_round_trip(&mut br, &[0x11]);
+        assert_eq!(&out[7..], &[0x11, 0x04, b'P', b'L', b'C', 0x00]);
+        let out = tcp_round_trip(&mut br, &[0x0B]);
+        assert_eq!(&out[7..], &[0x0B, 0x00, 0x00, 0x00, 0x03]);
+        // Fetching the counter didn't count.
+        let out = tcp_round_trip(&mut br, &[0x0B]);
+        assert_eq!(&out[7..], &[0x0B, 0x00, 0x00, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn test_comm_event_log(){
+        let mut br = BlankRegisters::new();
+        tcp_round_trip(&mut br, &[0x06, 0x00, 0x01, 0x00, 0x03]);
+        tcp_round_trip(&mut br, &[0x03, 0x00, 0x00, 0x00, 0x7E]);
+        let out = tcp_round_trip(&mut br, &[0x0C]);
+        // Newest first: this request, the exception, the write.
+        assert_eq!(&out[7..], &[0x0C, 0x0B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+                                0x80, 0x41, 0x80, 0x40, 0x80]);
+        // Restarting communications with 0xFF00 clears the log.
+        tcp_round_trip(&mut br, &[0x08, 0x00, 0x01, 0xFF, 0x00]);
+        let out = tcp_round_trip(&mut br, &[0x0C]);
+        assert_eq!(&out[7..], &[0x0C, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                0x80, 0x40, 0x00]);
+    }
+
+    #[test]
+    fn test_read_fifo_queue(){
+        let mut br = BlankRegisters::new();
+        assert!(!br.push_fifo(0x04DE, 0x01B8));
+        br.attach_fifo(0x04DE);
+        br.push_fifo(0x04DE, 0x01B8);
+        br.push_fifo(0x04DE, 0x1284);
+        let out = tcp_round_trip(&mut br, &[0x18, 0x04, 0xDE]);
+        assert_eq!(&out[7..], &[0x18, 0x00, 0x06, 0x00, 0x02, 0x01, 0xB8, 0x12, 0x84]);
+        // The pointer register holds the count.
+        let out = tcp_round_trip(&mut br, &[0x03, 0x04, 0xDE, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x03, 0x02, 0x00, 0x02]);
+        for i in 0..30 {
+            br.push_fifo(0x04DE, i);
+        }
+        let out = tcp_round_trip(&mut br, &[0x18, 0x04, 0xDE]);
+        assert_eq!(&out[7..], &[0x98, 0x03]);
+        assert_eq!(br.pop_fifo(0x04DE), Some(0x01B8));
+        let out = tcp_round_trip(&mut br, &[0x18, 0x04, 0xDE]);
+        assert_eq!(&out[7..11], &[0x18, 0x00, 0x40, 0x00]);
+        let out = tcp_round_trip(&mut br, &[0x18, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x98, 0x02]);
+    }
+
+    #[test]
+    fn test_file_record(){
+        let mut br = BlankRegisters::new();
+        br.set_file(4, vec![0; 10]);
+        br.set_file(3, vec![0; 12]);
+        // The examples from the specification.
+        let write = [0x15, 0x0D, 0x06, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03,
+                     0x06, 0xAF, 0x04, 0xBE, 0x10, 0x0D];
+        let out = tcp_round_trip(&mut br, &write);
+        assert_eq!(&out[7..], &write[..]);
+        assert_eq!(br.file(4).unwrap()[7..], [0x06AF, 0x04BE, 0x100D]);
+        tcp_round_trip(&mut br, &[0x15, 0x0B, 0x06, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02,
+                                  0x0D, 0xFE, 0x00, 0x20]);
+        tcp_round_trip(&mut br, &[0x15, 0x0B, 0x06, 0x00, 0x03, 0x00, 0x09, 0x00, 0x02,
+                                  0x33, 0xCD, 0x00, 0x40]);
+        let out = tcp_round_trip(&mut br, &[0x14, 0x0E,
+                                            0x06, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02,
+                                            0x06, 0x00, 0x03, 0x00, 0x09, 0x00, 0x02]);
+        assert_eq!(&out[7..], &[0x14, 0x0C, 0x05, 0x06, 0x0D, 0xFE, 0x00, 0x20,
+                                0x05, 0x06, 0x33, 0xCD, 0x00, 0x40]);
+        // Past the end of the file, or a file that doesn't exist.
+        let out = tcp_round_trip(&mut br, &[0x14, 0x07, 0x06, 0x00, 0x03, 0x00, 0x0B, 0x00, 0x02]);
+        assert_eq!(&out[7..], &[0x94, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x14, 0x07, 0x06, 0x00, 0x05, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x94, 0x02]);
+        // A bad reference type.
+        let out = tcp_round_trip(&mut br, &[0x14, 0x07, 0x05, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x94, 0x03]);
+        // A response that wouldn't fit in a PDU.
+        br.set_file(1, vec![0; 200]);
+        let out = tcp_round_trip(&mut br, &[0x14, 0x07, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7D]);
+        assert_eq!(&out[7..], &[0x94, 0x03]);
+        // A write with one bad sub-request changes nothing.
+        let out = tcp_round_trip(&mut br, &[0x15, 0x14,
+                                            0x06, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x12, 0x34,
+                                            0x06, 0x00, 0x04, 0x00, 0x09, 0x00, 0x02, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(&out[7..], &[0x95, 0x02]);
+        assert_eq!(br.file(4).unwrap()[0], 0);
+    }
+
+    // Answers holding register reads with the address, and nothing else.
+    struct AddressEcho;
+
+    impl ModbusBlock for AddressEcho {
+        fn read_holding_registers(&mut self, code: u8, address: u16, quantity: u16) -> ModbusResponsePDU {
+            ModbusResponsePDU::ReadHoldingRegistersResponse{
+                code: code,
+                byte_count: 2 * quantity as u8,
+                values: (address..address + quantity).collect()
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_block(){
+        let mut block = AddressEcho;
+        let pdu = ModbusRequestPDU{code: 0x03, address: 0x10, q_or_v: 2, addl: None};
+        assert_eq!(block.call(pdu).encode(), vec![0x03, 0x04, 0x00, 0x10, 0x00, 0x11]);
+        let pdu = ModbusRequestPDU{code: 0x06, address: 0x10, q_or_v: 2, addl: None};
+        assert_eq!(block.call(pdu).encode(), vec![0x86, 0x01]);
+        // Malformed data is still rejected before the block sees it.
+        let pdu = ModbusRequestPDU{code: 0x10, address: 0x10, q_or_v: 2, addl: None};
+        assert_eq!(block.call(pdu).encode(), vec![0x90, 0x03]);
+    }
+
+    #[test]
+    fn test_table_bounds(){
+        let mut br = BlankRegisters::builder()
+            .holding_registers(0, 200)
+            .coils(1000, 16)
+            .build();
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0xC6, 0x00, 0x02]);
+        assert_eq!(&out[7..], &[0x03, 0x04, 0x00, 0x00, 0x00, 0x00]);
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0xC7, 0x00, 0x02]);
+        assert_eq!(&out[7..], &[0x83, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0xC8, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x86, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x05, 0x03, 0xE8, 0xFF, 0x00]);
+        assert_eq!(&out[7..], &[0x05, 0x03, 0xE8, 0xFF, 0x00]);
+        let out = tcp_round_trip(&mut br, &[0x01, 0x03, 0xE7, 0x00, 0x02]);
+        assert_eq!(&out[7..], &[0x81, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x01, 0x03, 0xE8, 0x00, 0x10]);
+        assert_eq!(&out[7..], &[0x01, 0x02, 0x01, 0x00]);
+        // A quantity of 0 is out of range.
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x83, 0x03]);
+        let out = tcp_round_trip(&mut br, &[0x01, 0x03, 0xE8, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x81, 0x03]);
+        let out = tcp_round_trip(&mut br, &[0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(&out[7..], &[0x90, 0x03]);
+        // The whole address space can be set at once, but not from anywhere
+        // but the start.
+        let mut br = BlankRegisters::new();
+        assert!(!br.set_holding_registers(1, &vec![1; 0x10000]));
+        assert!(br.set_holding_registers(0, &vec![1; 0x10000]));
+        assert_eq!(br.holding_registers(0xFFFF, 1), Some(vec![1]));
+    }
+
+    #[test]
+    fn test_sparse_registers(){
+        let mut block = SparseRegisters::new();
+        block.add_holding_registers(0, 100);
+        block.add_holding_registers(1000, 100);
+        assert!(block.set_holding_register(1099, 7));
+        assert!(!block.set_holding_register(100, 7));
+        let read = |block: &mut SparseRegisters, address: u16, quantity: u16| {
+            let pdu = ModbusRequestPDU{code: 0x03, address: address, q_or_v: quantity, addl: None};
+            block.call(pdu).encode()
+        };
+        assert_eq!(read(&mut block, 1098, 2), vec![0x03, 0x04, 0x00, 0x00, 0x00, 0x07]);
+        assert_eq!(read(&mut block, 1099, 2), vec![0x83, 0x02]);
+        // Starts in a declared range but runs into the hole after it.
+        assert_eq!(read(&mut block, 99, 2), vec![0x83, 0x02]);
+        // A range touching an existing one joins it, keeping its values.
+        block.add_holding_registers(1100, 10);
+        assert_eq!(read(&mut block, 1099, 2), vec![0x03, 0x04, 0x00, 0x07, 0x00, 0x00]);
+        // Tables that were never declared have no addresses at all.
+        let pdu = ModbusRequestPDU{code: 0x01, address: 0, q_or_v: 1, addl: None};
+        assert_eq!(block.call(pdu).encode(), vec![0x81, 0x02]);
+    }
+
+    #[test]
+    fn test_config(){
+        let config = Config::from_json(r#"{
+            "units": [
+                { "unit_id": 1,
+                  "holding_registers": { "start": 100, "size": 10, "access": "read-only",
+                                         "values": [1, 2] },
+                  "coils": { "values": { "3": true } } },
+                { "unit_id": 2 }
+            ]
+        }"#).unwrap();
+        assert_eq!(config.units.len(), 2);
+        let mut br = config.units[0].build();
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0x64, 0x00, 0x03]);
+        assert_eq!(&out[7..], &[0x03, 0x06, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00]);
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0x64, 0x00, 0x03]);
+        assert_eq!(&out[7..], &[0x86, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x01, 0x00, 0x00, 0x00, 0x08]);
+        assert_eq!(&out[7..], &[0x01, 0x01, 0x08]);
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0x63, 0x00, 0x03]);
+        assert_eq!(&out[7..], &[0x86, 0x02]);
+
+        for bad in &[r#"{ "units": [ { "unit_id": 1 }, { "unit_id": 1 } ] }"#,
+                     r#"{ "units": [ { "unit_id": 1, "coils": { "size": 2, "values": [true, true, true] } } ] }"#,
+                     r#"{ "units": [ { "unit_id": 1, "input_registers": { "access": "read-write" } } ] }"#,
+                     r#"{ "units": [ { "unit_id": 1, "holding_registers": { "values": [70000] } } ] }"#,
+                     r#"{ "units": [ { "unit_id": 300 } ] }"#] {
+            match Config::from_json(bad) {
+                Err(ConfigError::Invalid(_)) => {},
+                other => panic!("{:?}", other)
+            }
+        }
+        match Config::from_json("{ \"units\": [") {
+            Err(ConfigError::Json(_)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_register_orders(){
+        // 0x41200000 is 10.0.
+        assert_eq!(10.0f32.to_registers(RegisterOrder::Abcd), vec![0x4120, 0x0000]);
+        assert_eq!(10.0f32.to_registers(RegisterOrder::Cdab), vec![0x0000, 0x4120]);
+        assert_eq!(10.0f32.to_registers(RegisterOrder::Badc), vec![0x2041, 0x0000]);
+        assert_eq!(10.0f32.to_registers(RegisterOrder::Dcba), vec![0x0000, 0x2041]);
+        assert_eq!(0x0102030405060708u64.to_registers(RegisterOrder::Cdab),
+                   vec![0x0708, 0x0506, 0x0304, 0x0102]);
+        for &order in &[RegisterOrder::Abcd, RegisterOrder::Cdab, RegisterOrder::Badc, RegisterOrder::Dcba] {
+            let registers = (-2i64).to_registers(order);
+            assert_eq!(i64::from_registers(&registers, order), Some(-2));
+            let registers = 1.5f64.to_registers(order);
+            assert_eq!(f64::from_registers(&registers, order), Some(1.5));
+        }
+        assert_eq!(u32::from_registers(&[0x0001], RegisterOrder::Abcd), None);
+
+        let mut br = BlankRegisters::builder().input_registers(0, 10).build();
+        assert!(br.set_holding_value(100, -100000i32, RegisterOrder::Cdab));
+        assert_eq!(br.holding_registers(100, 2), Some(vec![0x7960, 0xFFFE]));
+        assert_eq!(br.holding_value::<i32>(100, RegisterOrder::Cdab), Some(-100000));
+        assert!(!br.set_input_value(9, 1u32, RegisterOrder::Abcd));
+        assert!(br.set_input_string(0, "ABC", 3, RegisterOrder::Badc));
+        assert_eq!(br.input_registers(0, 3), Some(vec![0x4241, 0x0043, 0x0000]));
+        assert_eq!(br.input_string(0, 3, RegisterOrder::Badc), Some("ABC".to_string()));
+        assert!(!br.set_input_string(0, "ABCDEFG", 3, RegisterOrder::Abcd));
+    }
+
+    #[test]
+    fn test_access_rules(){
+        let mut br = BlankRegisters::new();
+        let mut rules = AccessRules::new();
+        rules.add(Table::HoldingRegisters, 100, 10, Access::ReadOnly);
+        rules.add_with_exception(Table::HoldingRegisters, 200, 1, Access::WriteOnly, 0x01);
+        rules.add(Table::Coils, 0, 8, Access::NoAccess);
+        br.set_access_rules(rules);
+        assert!(br.set_holding_registers(105, &[7]));
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0x64, 0x00, 0x0A]);
+        assert_eq!(&out[7..9], &[0x03, 0x14]);
+        // Writes overlapping the read-only range at all are refused.
+        let out = tcp_round_trip(&mut br, &[0x10, 0x00, 0x62, 0x00, 0x03, 0x06, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03]);
+        assert_eq!(&out[7..], &[0x90, 0x02]);
+        assert_eq!(br.holding_registers(98, 2), Some(vec![0, 0]));
+        let out = tcp_round_trip(&mut br, &[0x16, 0x00, 0x69, 0x00, 0xF2, 0x00, 0x25]);
+        assert_eq!(&out[7..], &[0x96, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0xC8, 0x12, 0x34]);
+        assert_eq!(&out[7..], &[0x06, 0x00, 0xC8, 0x12, 0x34]);
+        let out = tcp_round_trip(&mut br, &[0x03, 0x00, 0xC7, 0x00, 0x02]);
+        assert_eq!(&out[7..], &[0x83, 0x01]);
+        // Read/write is checked on both its ranges.
+        let out = tcp_round_trip(&mut br, &[0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x6D, 0x00, 0x01, 0x02, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x97, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x05, 0x00, 0x07, 0xFF, 0x00]);
+        assert_eq!(&out[7..], &[0x85, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x01, 0x00, 0x08, 0x00, 0x08]);
+        assert_eq!(&out[7..], &[0x01, 0x01, 0x00]);
+
+        let mut br = BlankRegisters::builder().coils(16, 8).read_only_coils().build();
+        let out = tcp_round_trip(&mut br, &[0x05, 0x00, 0x10, 0xFF, 0x00]);
+        assert_eq!(&out[7..], &[0x85, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0x10, 0x12, 0x34]);
+        assert_eq!(&out[7..], &[0x06, 0x00, 0x10, 0x12, 0x34]);
+        // Access rules set later don't make the table writable again.
+        let mut rules = AccessRules::new();
+        rules.add(Table::Coils, 20, 4, Access::NoAccess);
+        br.set_access_rules(rules);
+        let out = tcp_round_trip(&mut br, &[0x05, 0x00, 0x10, 0xFF, 0x00]);
+        assert_eq!(&out[7..], &[0x85, 0x02]);
+        let out = tcp_round_trip(&mut br, &[0x01, 0x00, 0x14, 0x00, 0x01]);
+        assert_eq!(&out[7..], &[0x81, 0x02]);
+    }
+
+    #[test]
+    fn test_write_hooks(){
+        let mut br = BlankRegisters::new();
+        // A setpoint limited to 0-1000, and a scaled register stored in tenths.
+        br.on_register_write(10, 1, |address, values| {
+            if values[(10 - address) as usize] > 1000 { WriteResult::Reject(0x04) } else { WriteResult::Accept }
+        });
+        br.on_register_write(20, 1, |address, values| {
+            values[(20 - address) as usize] *= 10;
+            WriteResult::Accept
+        });
+        let started = Arc::new(Mutex::new(false));
+        let flag = started.clone();
+        br.on_coil_write(5, 1, move |address, values| {
+            *flag.lock().unwrap() = values[(5 - address) as usize] == ::modbus::Coil::On;
+            WriteResult::Accept
+        });
+
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0x0A, 0x03, 0xE9]);
+        assert_eq!(&out[7..], &[0x86, 0x04]);
+        let out = tcp_round_trip(&mut br, &[0x06, 0x00, 0x0A, 0x03, 0xE8]);
+        assert_eq!(&out[7..], &[0x06, 0x00, 0x0A, 0x03, 0xE8]);
+        // A refused multiple write changes nothing, even outside the hook's range.
+        let out = tcp_round_trip(&mut br, &[0x10, 0x00, 0x09, 0x00, 0x02, 0x04, 0x00, 0x01, 0x07, 0xD0]);
+        assert_eq!(&out[7..], &[0x90, 0x04]);
+        assert_eq!(br.holding_registers(9, 2), Some(vec![0, 1000]));
+        let out = tcp_round_trip(&mut br, &[0x16, 0x00, 0x0A, 0x00, 0x00, 0xFF, 0xFF]);
+        assert_eq!(&out[7..], &[0x96, 0x04]);
+        tcp_round_trip(&mut br, &[0x10, 0x00, 0x13, 0x00, 0x02, 0x04, 0x00, 0x01, 0x00, 0x02]);
+        assert_eq!(br.holding_registers(19, 2), Some(vec![1, 20]));
+        tcp_round_trip(&mut br, &[0x05, 0x00, 0x05, 0xFF, 0x00]);
+        assert!(*started.lock().unwrap());
+        tcp_round_trip(&mut br, &[0x0F, 0x00, 0x04, 0x00, 0x02, 0x01, 0x01]);
+        assert!(!*started.lock().unwrap());
+    }
+
+    #[test]
+    fn test_change_stream(){
+        let mut br = BlankRegisters::new();
+        br.set_unit_id(0x11);
+        let changes = br.subscribe();
+        br.set_holding_registers(3, &[0x0F0F]);
+        tcp_round_trip(&mut br, &[0x06, 0x00, 0x01, 0x12, 0x34]);
+        tcp_round_trip(&mut br, &[0x0F, 0x00, 0x00, 0x00, 0x03, 0x01, 0x05]);
+        tcp_round_trip(&mut br, &[0x16, 0x00, 0x03, 0x00, 0xFF, 0x00, 0x00]);
+        // Refused writes aren't changes.
+        tcp_round_trip(&mut br, &[0x05, 0x00, 0x00, 0x12, 0x34]);
+        drop(br);
+        let changes: Vec<Change> = changes.wait().map(|change| change.unwrap()).collect();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0], Change{
+            unit_id: Some(0x11), peer: None, table: Table::HoldingRegisters,
+            address: 1, old_values: vec![0], new_values: vec![0x1234]
+        });
+        assert_eq!((changes[1].table, changes[1].address), (Table::Coils, 0));
+        assert_eq!(changes[1].new_values, vec![1, 0, 1]);
+        assert_eq!((changes[2].old_values[0], changes[2].new_values[0]), (0x0F0F, 0x000F));
+    }
+
+    #[test]
+    fn test_registers_handle(){
+        let handle = RegistersHandle::new(BlankRegisters::builder().input_registers(100, 10).build());
+        let block = handle.shared();
+        let app = handle.clone();
+        thread::spawn(move || {
+            assert!(app.set_input_registers(100, &[1, 2, 3]));
+            assert!(app.set_discrete_input(7, ::modbus::Coil::On));
+            assert!(!app.set_input_register(110, 4));
+        }).join().unwrap();
+        let resp = tcp_round_trip(&mut *block.lock().unwrap(), &[0x04, 0x00, 0x64, 0x00, 0x03]);
+        assert_eq!(&resp[7..], &[0x04, 0x06, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03][..]);
+        let resp = tcp_round_trip(&mut *block.lock().unwrap(), &[0x02, 0x00, 0x07, 0x00, 0x01]);
+        assert_eq!(&resp[7..], &[0x02, 0x01, 0x01][..]);
+        tcp_round_trip(&mut *block.lock().unwrap(), &[0x06, 0x00, 0x02, 0x12, 0x34]);
+        tcp_round_trip(&mut *block.lock().unwrap(), &[0x05, 0x00, 0x01, 0xFF, 0x00]);
+        assert_eq!(handle.holding_registers(2, 1), Some(vec![0x1234]));
+        assert_eq!(handle.coils(0, 2), Some(vec![::modbus::Coil::Off, ::modbus::Coil::On]));
+        assert_eq!(handle.input_registers(108, 3), None);
+        handle.update(|block| block.set_input_value(104, 1.5f32, RegisterOrder::Abcd));
+        assert_eq!(handle.input_registers(104, 2), Some(vec![0x3FC0, 0x0000]));
+    }
+
+    #[test]
+    fn test_snapshot(){
+        let small = || BlankRegisters::builder()
+            .coils(0, 10).discrete_inputs(0, 0).holding_registers(100, 3).input_registers(0, 1).build();
+        let mut br = small();
+        br.set_coils(8, &[::modbus::Coil::On]);
+        br.set_holding_registers(100, &[1, 0xABCD, 3]);
+        let data = br.snapshot().encode();
+        assert_eq!(&data[..5], b"MBSS\x01");
+        // Two bytes of coils, none of discrete inputs.
+        assert_eq!(data.len(), 5 + (6 + 2) + 6 + (6 + 6) + (6 + 2) + 2);
+        let snapshot = Snapshot::decode(&data).unwrap();
+        assert_eq!(snapshot, br.snapshot());
+        let mut damaged = data.clone();
+        damaged[20] ^= 1;
+        assert!(match Snapshot::decode(&damaged) { Err(SnapshotError::Corrupt) => true, _ => false });
+        assert!(match Snapshot::decode(&data[..data.len() - 1]) { Err(SnapshotError::Corrupt) => true, _ => false });
+
+        let mut restored = small();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.holding_registers(100, 3), Some(vec![1, 0xABCD, 3]));
+        assert_eq!(restored.coils(7, 2), Some(vec![::modbus::Coil::Off, ::modbus::Coil::On]));
+        let mut other = BlankRegisters::builder().holding_registers(100, 4).build();
+        assert!(match other.restore(&snapshot) { Err(SnapshotError::Mismatch) => true, _ => false });
+
+        // Named after the time, so that test runs at once don't share it.
+        let now = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap();
+        let path = ::std::env::temp_dir().join(format!("modbus_server_test_snapshot_{}_{}",
+                                                       now.as_secs(), now.subsec_nanos()));
+        snapshot.save(&path).unwrap();
+        assert_eq!(Snapshot::load(&path).unwrap(), snapshot);
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(match Snapshot::load(&path) { Err(SnapshotError::Io(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn test_simulation(){
+        let mut sim = Simulation::new();
+        sim.add(Table::InputRegisters, 0, Waveform::Sine { min: 0, max: 1000, period: 4.0 }).unwrap();
+        sim.add(Table::InputRegisters, 1, Waveform::Ramp { min: 100, max: 200, period: 10.0 }).unwrap();
+        sim.add(Table::InputRegisters, 2, Waveform::Counter { min: 5, max: 7, step: 1, interval: 1.0 }).unwrap();
+        sim.add(Table::InputRegisters, 3, Waveform::Steps { values: vec![10, 20, 30], interval: 2.0 }).unwrap();
+        sim.add(Table::InputRegisters, 4, Waveform::RandomWalk { min: 0, max: 10, step: 3 }).unwrap();
+        sim.add(Table::DiscreteInputs, 0, Waveform::Square { min: 0, max: 1, period: 2.0 }).unwrap();
+        // Waveforms that would fail on the first step are refused.
+        assert_eq!(sim.add(Table::InputRegisters, 5, Waveform::Steps { values: vec![], interval: 1.0 }),
+                   Err(WaveformError::NoValues));
+        assert_eq!(sim.add(Table::InputRegisters, 5, Waveform::Counter { min: 0, max: 9, step: 1, interval: 0.0 }),
+                   Err(WaveformError::NotPositive));
+        assert_eq!(sim.add(Table::InputRegisters, 5, Waveform::Sine { min: 0, max: 9, period: ::std::f64::NAN }),
+                   Err(WaveformError::NotPositive));
+        assert_eq!(sim.add(Table::InputRegisters, 5, Waveform::RandomWalk { min: 9, max: 0, step: 1 }),
+                   Err(WaveformError::MinAboveMax));
+        let mut br = BlankRegisters::new();
+        sim.step(&mut br, Duration::from_secs(1));
+        assert_eq!(br.input_registers(0, 4), Some(vec![1000, 110, 6, 10]));
+        assert_eq!(br.discrete_inputs(0, 1), Some(vec![::modbus::Coil::Off]));
+        sim.step(&mut br, Duration::from_secs(6));
+        assert_eq!(br.input_registers(0, 4), Some(vec![500, 160, 5, 10]));
+        assert_eq!(br.discrete_inputs(0, 1), Some(vec![::modbus::Coil::On]));
+        let mut last = br.input_registers(4, 1).unwrap()[0];
+        for _ in 0..100 {
+            sim.step(&mut br, Duration::from_secs(8));
+            let value = br.input_registers(4, 1).unwrap()[0];
+            assert!(value <= 10 && (value as i32 - last as i32).abs() <= 3);
+            last = value;
+        }
+
+        let config = Config::from_json(r#"{"units": [{"unit_id": 1,
+            "input_registers": {"start": 10, "size": 2},
+            "simulations": [{"table": "input_registers", "address": 11,
+                             "waveform": "steps", "values": [4, 5]}]}]}"#).unwrap();
+        let mut br = config.units[0].build();
+        config.units[0].simulation.clone().step(&mut br, Duration::from_secs(1));
+        assert_eq!(br.input_registers(11, 1), Some(vec![5]));
+        for bad in &[r#"{"table": "input_registers", "address": 12, "waveform": "steps", "values": [4]}"#,
+                     r#"{"table": "input_registers", "address": 10, "waveform": "sine"}"#,
+                     r#"{"table": "input_registers", "address": 10, "waveform": "ramp", "period": 0}"#,
+                     r#"{"table": "input_registers", "address": 10, "waveform": "counter", "min": 2, "max": 1}"#,
+                     r#"{"table": "input_registers", "address": 10, "waveform": "noise"}"#] {
+            let json = format!(r#"{{"units": [{{"unit_id": 1, "input_registers": {{"start": 10, "size": 2}},
+                                  "simulations": [{}]}}]}}"#, bad);
+            assert!(match Config::from_json(&json) { Err(ConfigError::Invalid(_)) => true, _ => false });
+        }
+    }
 }
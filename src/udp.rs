@@ -0,0 +1,136 @@
+// Modbus/UDP: every datagram carries one MBAP header and request PDU, and
+// the response goes back to the sender with the same transaction id.
+
+use std::net::SocketAddr;
+use std::io;
+use std::sync::Arc;
+use futures::{Async, Future, Poll};
+use tokio_core::net::{UdpSocket, UdpCodec};
+use tokio_service::Service;
+
+use {ModbusTCPRequest, ModbusTCPResponse, Diagnostics};
+use parse_tcp_request;
+
+#[derive(Default)]
+pub struct ModbusUDPCodec {
+    diagnostics: Arc<Diagnostics>
+}
+
+impl ModbusUDPCodec {
+    pub fn new(diagnostics: Arc<Diagnostics>) -> ModbusUDPCodec {
+        ModbusUDPCodec { diagnostics: diagnostics }
+    }
+}
+
+impl UdpCodec for ModbusUDPCodec {
+    // Datagrams that don't hold exactly one well-formed request decode to
+    // None, so that one bad master can't stop the socket serving the rest.
+    type In = (SocketAddr, Option<ModbusTCPRequest>);
+    type Out = (SocketAddr, ModbusTCPResponse);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        match parse_tcp_request(buf) {
+            Ok(req) => {
+                self.diagnostics.count_bus_message();
+                Ok((*src, Some(req)))
+            },
+            Err(_) => {
+                self.diagnostics.count_bus_communication_error();
+                Ok((*src, None))
+            }
+        }
+    }
+
+    fn encode(&mut self, (addr, item): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        into.extend_from_slice(&item.encode());
+        addr
+    }
+}
+
+// Large enough for any MBAP header and PDU.
+const MAX_DATAGRAM_LEN: usize = 260;
+
+// Serves one datagram at a time. An error receiving or answering one
+// datagram is reported and that datagram dropped, so that a master that
+// went away can't stop the socket serving the rest.
+struct UdpServer<F, S: Service> {
+    socket: UdpSocket,
+    new_service: F,
+    codec: ModbusUDPCodec,
+    buf: Vec<u8>,
+    // The request being served, then its response waiting to be sent.
+    pending: Option<(SocketAddr, S::Future)>,
+    reply: Option<(SocketAddr, Vec<u8>)>
+}
+
+impl<F, S> Future for UdpServer<F, S>
+    where F: Fn(SocketAddr) -> S,
+          S: Service<Request = ModbusTCPRequest,
+                     Response = ModbusTCPResponse,
+                     Error = io::Error>
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if let Some((addr, data)) = self.reply.take() {
+                match self.socket.send_to(&data, &addr) {
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.reply = Some((addr, data));
+                        return Ok(Async::NotReady);
+                    },
+                    Err(e) => println!("can't answer {}: {}", addr, e)
+                }
+                continue;
+            }
+            if let Some((addr, mut future)) = self.pending.take() {
+                match future.poll() {
+                    Ok(Async::Ready(resp)) => {
+                        let mut data = Vec::new();
+                        let addr = self.codec.encode((addr, resp), &mut data);
+                        self.reply = Some((addr, data));
+                    },
+                    Ok(Async::NotReady) => {
+                        self.pending = Some((addr, future));
+                        return Ok(Async::NotReady);
+                    },
+                    Err(e) => println!("can't serve {}: {}", addr, e)
+                }
+                continue;
+            }
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((n, addr)) => {
+                    if let Ok((addr, Some(req))) = self.codec.decode(&addr, &self.buf[..n]) {
+                        self.pending = Some((addr, (self.new_service)(addr).call(req)));
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => println!("can't receive: {}", e)
+            }
+        }
+    }
+}
+
+// Answer every request arriving on `socket` through the service
+// `new_service` makes for its sender, which can be the same kind of service
+// the TCP side is using. Traffic is counted in `diagnostics`. The future
+// never ends.
+pub fn serve_udp<F, S>(socket: UdpSocket, new_service: F, diagnostics: Arc<Diagnostics>)
+                       -> Box<Future<Item = (), Error = io::Error>>
+    where F: Fn(SocketAddr) -> S + 'static,
+          S: Service<Request = ModbusTCPRequest,
+                     Response = ModbusTCPResponse,
+                     Error = io::Error> + 'static,
+          S::Future: 'static
+{
+    Box::new(UdpServer {
+        socket: socket,
+        new_service: new_service,
+        codec: ModbusUDPCodec::new(diagnostics),
+        buf: vec![0; MAX_DATAGRAM_LEN],
+        pending: None,
+        reply: None
+    })
+}
@@ -0,0 +1,135 @@
+// Values wider than a register, spread over consecutive registers.
+//
+// Devices disagree about how to do this. Writing the bytes of a value from
+// most to least significant as A, B, C, D, the four orders in use are:
+//
+//   Abcd  big endian: A B in the first register, C D in the second.
+//   Cdab  word swapped: C D first, then A B.
+//   Badc  byte swapped: B A first, then D C.
+//   Dcba  little endian: D C first, then B A.
+//
+// 64 bit values extend the same pattern to four registers.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use {Quantity, Value};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RegisterOrder {
+    Abcd,
+    Cdab,
+    Badc,
+    Dcba
+}
+
+impl RegisterOrder {
+    fn words_swapped(&self) -> bool {
+        *self == RegisterOrder::Cdab || *self == RegisterOrder::Dcba
+    }
+
+    fn bytes_swapped(&self) -> bool {
+        *self == RegisterOrder::Badc || *self == RegisterOrder::Dcba
+    }
+}
+
+impl Default for RegisterOrder {
+    // What the Modbus specification itself uses.
+    fn default() -> RegisterOrder {
+        RegisterOrder::Abcd
+    }
+}
+
+// Big endian bytes, two to a register.
+fn to_registers(bytes: &[u8], order: RegisterOrder) -> Vec<Value> {
+    let mut registers: Vec<Value> = bytes.chunks(2).map(|pair| {
+        let (high, low) = if order.bytes_swapped() { (pair[1], pair[0]) } else { (pair[0], pair[1]) };
+        (high as Value) << 8 | low as Value
+    }).collect();
+    if order.words_swapped() {
+        registers.reverse();
+    }
+    registers
+}
+
+fn from_registers(registers: &[Value], order: RegisterOrder) -> Vec<u8> {
+    let mut registers = registers.to_vec();
+    if order.words_swapped() {
+        registers.reverse();
+    }
+    let mut bytes = Vec::with_capacity(2 * registers.len());
+    for register in registers {
+        let (high, low) = ((register >> 8) as u8, (register & 0xff) as u8);
+        if order.bytes_swapped() {
+            bytes.push(low);
+            bytes.push(high);
+        } else {
+            bytes.push(high);
+            bytes.push(low);
+        }
+    }
+    bytes
+}
+
+pub trait RegisterValue: Sized {
+    // How many registers the value takes up.
+    fn register_count() -> Quantity;
+
+    fn to_registers(&self, order: RegisterOrder) -> Vec<Value>;
+
+    // None if there are too few registers.
+    fn from_registers(registers: &[Value], order: RegisterOrder) -> Option<Self>;
+}
+
+macro_rules! register_value {
+    ($t:ty, $count:expr, $write:ident, $read:ident) => {
+        impl RegisterValue for $t {
+            fn register_count() -> Quantity {
+                $count
+            }
+
+            fn to_registers(&self, order: RegisterOrder) -> Vec<Value> {
+                let mut bytes = [0; 2 * $count];
+                BigEndian::$write(&mut bytes, *self);
+                to_registers(&bytes, order)
+            }
+
+            fn from_registers(registers: &[Value], order: RegisterOrder) -> Option<$t> {
+                if registers.len() < $count {
+                    return None;
+                }
+                Some(BigEndian::$read(&from_registers(&registers[..$count], order)))
+            }
+        }
+    }
+}
+
+register_value!(u32, 2, write_u32, read_u32);
+register_value!(i32, 2, write_i32, read_i32);
+register_value!(f32, 2, write_f32, read_f32);
+register_value!(u64, 4, write_u64, read_u64);
+register_value!(i64, 4, write_i64, read_i64);
+register_value!(f64, 4, write_f64, read_f64);
+
+// An ASCII string in `count` registers, two characters to a register and
+// padded with NULs. Only the byte order applies: the first characters go
+// in the first register whatever the word order. None if the string
+// doesn't fit or isn't ASCII.
+pub fn string_to_registers(value: &str, count: Quantity, order: RegisterOrder) -> Option<Vec<Value>> {
+    if value.bytes().any(|b| b >= 0x80) || value.len() > 2 * count as usize {
+        return None;
+    }
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.resize(2 * count as usize, 0);
+    Some(to_registers(&bytes, string_order(order)))
+}
+
+// Stops at the first NUL. Bytes outside ASCII come back as U+FFFD.
+pub fn string_from_registers(registers: &[Value], order: RegisterOrder) -> String {
+    let bytes = from_registers(registers, string_order(order));
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end].iter().map(|&b| if b < 0x80 { b as char } else { '\u{FFFD}' }).collect()
+}
+
+fn string_order(order: RegisterOrder) -> RegisterOrder {
+    if order.bytes_swapped() { RegisterOrder::Badc } else { RegisterOrder::Abcd }
+}